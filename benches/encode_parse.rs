@@ -0,0 +1,56 @@
+//! Throughput of MessageParser::encode and parse, for protocol 3.1 and 3.3. Run with
+//! `cargo bench`. The benchmarks use a plain timing loop, so they run on stable Rust without
+//! extra dependencies.
+use rust_tuyapi::mesparse::{CommandType, Message, MessageParser};
+use rust_tuyapi::PayloadStruct;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20_000;
+
+const BATCHES: u32 = 10;
+
+/// Report the fastest of several batches, which is the least disturbed by other processes.
+fn bench(name: &str, mut f: impl FnMut()) {
+    // Warm up caches and the allocator
+    for _ in 0..ITERATIONS / 10 {
+        f();
+    }
+    let mut per_iter = Duration::MAX;
+    for _ in 0..BATCHES {
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            f();
+        }
+        per_iter = per_iter.min(start.elapsed() / ITERATIONS);
+    }
+    println!(
+        "{:<24} {:>8?}/iter {:>10.0} iter/s",
+        name,
+        per_iter,
+        1.0 / per_iter.max(Duration::from_nanos(1)).as_secs_f64()
+    );
+}
+
+fn main() {
+    let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+        .gw_id("002004265ccf7fb1b659")
+        .uid("002004265ccf7fb1b659")
+        .timestamp_now()
+        .dp("1", true)
+        .dp("2", 255)
+        .dp("3", "white")
+        .build();
+    for (version, encrypt) in [("3.1", false), ("3.1", true), ("3.3", true)] {
+        let mp = MessageParser::create(version, Some("bbe88b3f4106d354")).unwrap();
+        let mes = Message::new(payload.clone(), CommandType::Control, Some(1));
+        let encoded = mp.encode(&mes, encrypt).unwrap();
+        let suffix = if encrypt { "" } else { " plain" };
+        bench(&format!("encode {}{}", version, suffix), || {
+            black_box(mp.encode(black_box(&mes), encrypt).unwrap());
+        });
+        bench(&format!("parse {}{}", version, suffix), || {
+            black_box(mp.parse(black_box(&encoded)).unwrap());
+        });
+    }
+}
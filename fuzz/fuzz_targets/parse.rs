@@ -0,0 +1,19 @@
+//! Feeds arbitrary bytes to the parse functions of MessageParser, which must return an error
+//! rather than panic on malformed input. Run with `cargo +nightly fuzz run parse` in this
+//! directory. The first byte selects the protocol version.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_tuyapi::mesparse::MessageParser;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((version, buf)) = data.split_first() else {
+        return;
+    };
+    let version = ["3.1", "3.2", "3.3", "3.4", "3.5"][*version as usize % 5];
+    let mp = MessageParser::create(version, Some("bbe88b3f4106d354")).unwrap();
+    let _ = mp.parse(buf);
+    let _ = mp.parse_best_effort(buf);
+    let _ = mp.parse_stream(buf);
+    let _ = mp.parse_resync(buf);
+});
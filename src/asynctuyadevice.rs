@@ -0,0 +1,299 @@
+//! # AsyncTuyaDevice
+//! The AsyncTuyaDevice is the async version of the TuyaDevice, enabled with the `async` feature.
+//! It encodes and parses messages with the same MessageParser, but sends and receives them
+//! through an AsyncTuyaTransport.
+//!
+//! ## Example
+//! The crate does not ship a transport for any runtime. Using tokio, a transport is a thin
+//! wrapper around a TcpStream.
+//! ```ignore
+//! struct TokioTransport(tokio::net::TcpStream);
+//!
+//! impl AsyncTuyaTransport for TokioTransport {
+//!     async fn connect(addr: SocketAddr) -> Result<Self> {
+//!         Ok(TokioTransport(tokio::net::TcpStream::connect(addr).await?))
+//!     }
+//!     async fn do_send(&mut self, buf: &[u8]) -> Result<usize> {
+//!         Ok(self.0.write(buf).await?)
+//!     }
+//!     async fn do_read(&mut self, buf: &mut [u8]) -> Result<usize> {
+//!         Ok(self.0.read(buf).await?)
+//!     }
+//!     async fn teardown(&mut self) -> Result<()> {
+//!         Ok(self.0.shutdown().await?)
+//!     }
+//! }
+//!
+//! let device: AsyncTuyaDevice<TokioTransport> =
+//!     AsyncTuyaDevice::create("3.3", Some("fedcba987654321"), ip)?;
+//! device.set(payload, 0).await?;
+//! ```
+use crate::error::ErrorKind;
+use crate::mesparse::{CommandType, Message, MessageParser, TuyaVersion};
+use crate::transports::AsyncTuyaTransport;
+use crate::tuyadevice::MAX_DATAGRAM_LEN;
+use crate::{log_addr, log_hex, Payload, Result};
+use log::{debug, info, warn};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+pub struct AsyncTuyaDevice<T> {
+    mp: MessageParser,
+    addr: SocketAddr,
+    transport: PhantomData<fn() -> T>,
+}
+
+impl<T: AsyncTuyaTransport> AsyncTuyaDevice<T> {
+    /// Fails with ErrorKind::KeyRequiredForVersion if key is None for a version above 3.1.
+    pub fn create(ver: &str, key: Option<&str>, addr: IpAddr) -> Result<AsyncTuyaDevice<T>> {
+        let version = TuyaVersion::from_str(ver)?;
+        if key.is_none() && version.requires_key() {
+            return Err(ErrorKind::KeyRequiredForVersion(version));
+        }
+        let mp = MessageParser::create(ver, key)?;
+        Ok(AsyncTuyaDevice::create_with_mp(
+            mp,
+            SocketAddr::new(addr, 6668),
+        ))
+    }
+
+    pub fn create_with_mp(mp: MessageParser, addr: SocketAddr) -> AsyncTuyaDevice<T> {
+        AsyncTuyaDevice {
+            mp,
+            addr,
+            transport: PhantomData,
+        }
+    }
+
+    pub async fn set(&self, tuya_payload: Payload, seq_id: u32) -> Result<()> {
+        let mes = Message::new(tuya_payload, self.mp.set_commandtype(), Some(seq_id));
+        let replies = match self.send(&mes, seq_id).await {
+            Err(ErrorKind::EmptyReply) => {
+                warn!(
+                    "The device closed the connection without replying, assuming the command was applied ({})",
+                    seq_id
+                );
+                return Ok(());
+            }
+            result => result?,
+        };
+        replies
+            .iter()
+            .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
+        match replies.iter().find_map(Message::device_error) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn get(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<Message>> {
+        let mes = Message::new(tuya_payload, self.mp.get_commandtype(), Some(seq_id));
+        let replies = self.send(&mes, seq_id).await?;
+        replies
+            .iter()
+            .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
+        Ok(replies)
+    }
+
+    pub async fn refresh(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<Message>> {
+        let mes = Message::new(tuya_payload, CommandType::DpRefresh, Some(seq_id));
+        let replies = self.send(&mes, seq_id).await?;
+        replies
+            .iter()
+            .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
+        Ok(replies)
+    }
+
+    async fn send(&self, mes: &Message, seq_id: u32) -> Result<Vec<Message>> {
+        let mut transport = T::connect(self.addr).await?;
+        let session_mp = match self.mp.version() {
+            TuyaVersion::ThreeFour | TuyaVersion::ThreeFive => {
+                Some(self.negotiate_session_key(&mut transport, seq_id).await?)
+            }
+            _ => None,
+        };
+        let mp = session_mp.as_ref().unwrap_or(&self.mp);
+        info!(
+            "Writing message to {} ({}):\n{}",
+            log_addr(&self.addr),
+            seq_id,
+            &mes
+        );
+        let bts = transport.do_send(mp.encode(mes, true)?.as_ref()).await?;
+        info!("Wrote {} bytes ({})", bts, seq_id);
+        let buf = read_reply(&mut transport, mp).await?;
+        info!("Received {} bytes ({})", buf.len(), seq_id);
+        debug!("Received response ({}):\n{}", seq_id, log_hex(&buf));
+        let replies = mp.parse(&buf)?;
+        debug!("Shutting down connection ({})", seq_id);
+        // The reply is already received, a failed shutdown does not change that
+        if let Err(e) = transport.teardown().await {
+            warn!("Shutting down the connection failed ({}): {}", seq_id, e);
+        }
+        Ok(replies)
+    }
+
+    async fn negotiate_session_key(&self, transport: &mut T, seq_id: u32) -> Result<MessageParser> {
+        let mut local_nonce = [0; 16];
+        crate::cipher::rand_bytes(&mut local_nonce)?;
+        debug!(
+            "Negotiating session key with {} ({})",
+            log_addr(&self.addr),
+            seq_id
+        );
+        transport
+            .do_send(&self.mp.encode_session_key_start(&local_nonce, seq_id)?)
+            .await?;
+        let buf = match read_reply(transport, &self.mp).await {
+            Err(ErrorKind::EmptyReply) => return Err(ErrorKind::BadTcpRead),
+            buf => buf?,
+        };
+        let (finish, session_key) =
+            self.mp
+                .encode_session_key_finish(&local_nonce, &buf, seq_id)?;
+        transport.do_send(&finish).await?;
+        let mut mp = self.mp.clone();
+        mp.set_session_key(session_key);
+        Ok(mp)
+    }
+}
+
+/// Read from the transport until the received bytes parse as one or more complete messages or
+/// the device closes the connection, like the blocking read_reply. Returns ErrorKind::EmptyReply
+/// if the device closed the connection without sending anything.
+async fn read_reply<T: AsyncTuyaTransport>(
+    transport: &mut T,
+    mp: &MessageParser,
+) -> Result<Vec<u8>> {
+    let mut reply = vec![];
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        let bts = transport.do_read(&mut buf).await?;
+        if bts == 0 {
+            break;
+        }
+        reply.extend(&buf[..bts]);
+        // Stop as soon as the reply is made up of whole frames, or can never become valid
+        match mp.parse_stream(&reply) {
+            Ok((messages, used)) if !messages.is_empty() && used == reply.len() => break,
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    if reply.is_empty() {
+        return Err(ErrorKind::EmptyReply);
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PayloadStruct;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    thread_local! {
+        static REPLY: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static SENT: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        static CHUNK_LEN: RefCell<usize> = const { RefCell::new(usize::MAX) };
+    }
+
+    struct FakeTransport;
+
+    impl AsyncTuyaTransport for FakeTransport {
+        async fn connect(_addr: SocketAddr) -> Result<Self> {
+            Ok(FakeTransport)
+        }
+        async fn do_send(&mut self, buf: &[u8]) -> Result<usize> {
+            SENT.with(|sent| sent.borrow_mut().extend(buf));
+            Ok(buf.len())
+        }
+        async fn do_read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            // Hands out the reply CHUNK_LEN bytes at a time, like a reply split over reads
+            let len = CHUNK_LEN.with(|len| *len.borrow());
+            REPLY.with(|reply| {
+                let mut reply = reply.borrow_mut();
+                let bts = reply.len().min(buf.len()).min(len);
+                buf[..bts].copy_from_slice(&reply[..bts]);
+                reply.drain(..bts);
+                Ok(bts)
+            })
+        }
+        async fn teardown(&mut self) -> Result<()> {
+            // A failed shutdown must not fail the request
+            Err(ErrorKind::TcpError(std::io::ErrorKind::NotConnected.into()))
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = pin!(fut);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+                return res;
+            }
+        }
+    }
+
+    #[test]
+    fn get_sends_query_and_parses_reply() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message {
+            payload: PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            command: Some(CommandType::DpQuery),
+            raw_command: None,
+            seq_nr: Some(1),
+            ret_code: None,
+        };
+        REPLY.with(|r| *r.borrow_mut() = mp.encode(&reply, true).unwrap());
+        let device: AsyncTuyaDevice<FakeTransport> =
+            AsyncTuyaDevice::create_with_mp(mp.clone(), "127.0.0.1:6668".parse().unwrap());
+
+        let replies = block_on(device.get(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            1,
+        ))
+        .unwrap();
+
+        assert_eq!(replies, vec![reply]);
+        let sent = SENT.with(|sent| mp.parse(&sent.borrow()).unwrap());
+        assert_eq!(sent[0].command, Some(CommandType::DpQuery));
+        assert_eq!(sent[0].seq_nr, Some(1));
+    }
+
+    #[test]
+    fn get_joins_a_reply_split_over_reads() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message {
+            payload: (1..40)
+                .fold(PayloadStruct::builder("002004265ccf7fb1b659"), |b, dp| {
+                    b.dp(&dp.to_string(), dp)
+                })
+                .build(),
+            command: Some(CommandType::DpQuery),
+            raw_command: None,
+            seq_nr: Some(2),
+            ret_code: None,
+        };
+        let encoded = mp.encode(&reply, true).unwrap();
+        assert!(encoded.len() > 256);
+        REPLY.with(|r| *r.borrow_mut() = encoded);
+        CHUNK_LEN.with(|len| *len.borrow_mut() = 100);
+        let device: AsyncTuyaDevice<FakeTransport> =
+            AsyncTuyaDevice::create_with_mp(mp, "127.0.0.1:6668".parse().unwrap());
+
+        let replies = block_on(device.get(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            2,
+        ))
+        .unwrap();
+
+        assert_eq!(replies, vec![reply]);
+    }
+}
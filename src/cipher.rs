@@ -1,21 +1,49 @@
-use crate::mesparse::TuyaVersion;
+use crate::error::ErrorKind;
+use crate::mesparse::{md5_digest, md5_header, TuyaVersion};
 use crate::Result;
+#[cfg(feature = "crypto")]
 use base64::{engine::general_purpose, Engine as _};
-use openssl::symm::{decrypt, encrypt, Cipher};
+#[cfg(feature = "crypto")]
+use openssl::hash::MessageDigest;
+#[cfg(feature = "crypto")]
+use openssl::pkey::PKey;
+#[cfg(feature = "crypto")]
+use openssl::sign::Signer;
+#[cfg(feature = "crypto")]
+use openssl::symm::{decrypt, decrypt_aead, encrypt, encrypt_aead, Cipher};
 
-/// TuyaCipher is a low level api for encrypting and decrypting Vec<u8>'s.
+/// TuyaCipher is a low level api for encrypting and decrypting Vec<u8>'s. Without the `crypto`
+/// feature it only keeps the keys, and everything that encrypts, decrypts or signs fails with
+/// ErrorKind::CryptoDisabled.
+#[derive(Clone)]
 pub(crate) struct TuyaCipher {
     key: Vec<u8>,
     version: TuyaVersion,
-    cipher: Cipher,
+    session_key: Option<Vec<u8>>,
 }
 
-fn maybe_strip_header(version: &TuyaVersion, data: &[u8]) -> Vec<u8> {
-    if data.len() > 3 && &data[..3] == version.as_bytes() {
-        match version {
-            TuyaVersion::ThreeOne => data.split_at(19).1.to_vec(),
-            TuyaVersion::ThreeThree => data.split_at(15).1.to_vec(),
-        }
+/// Fill buf with random bytes, e.g. the nonce of the session key negotiation.
+#[cfg(feature = "crypto")]
+pub(crate) fn rand_bytes(buf: &mut [u8]) -> Result<()> {
+    Ok(openssl::rand::rand_bytes(buf)?)
+}
+
+#[cfg(not(feature = "crypto"))]
+pub(crate) fn rand_bytes(_buf: &mut [u8]) -> Result<()> {
+    Err(ErrorKind::CryptoDisabled)
+}
+
+pub(crate) fn maybe_strip_header(version: &TuyaVersion, data: &[u8]) -> Vec<u8> {
+    let header_len = match version {
+        TuyaVersion::ThreeOne => 19,
+        TuyaVersion::ThreeTwo
+        | TuyaVersion::ThreeThree
+        | TuyaVersion::ThreeFour
+        | TuyaVersion::ThreeFive => 15,
+    };
+    // Data shorter than the header can not have one, even if it starts with the version
+    if data.len() >= header_len && &data[..3] == version.as_bytes() {
+        data.split_at(header_len).1.to_vec()
     } else {
         data.to_vec()
     }
@@ -26,52 +54,193 @@ impl TuyaCipher {
         TuyaCipher {
             key: key.to_vec(),
             version,
-            cipher: Cipher::aes_128_ecb(),
+            session_key: None,
+        }
+    }
+
+    /// A cipher with the same local key, for another version.
+    pub fn with_version(&self, version: TuyaVersion) -> TuyaCipher {
+        TuyaCipher::create(&self.key, version)
+    }
+
+    /// Set the key negotiated with the device in protocol 3.4. Once set, it is used instead of
+    /// the local key for encryption and HMAC calculation.
+    pub fn set_session_key(&mut self, session_key: Vec<u8>) {
+        self.session_key = Some(session_key);
+    }
+
+    /// The key negotiated with the device, for troubleshooting the 3.4 and 3.5 handshake.
+    #[cfg(any(all(test, feature = "crypto"), feature = "session-key"))]
+    pub fn session_key(&self) -> Option<&[u8]> {
+        self.session_key.as_deref()
+    }
+
+    pub fn md5(&self, payload: &[u8]) -> Vec<u8> {
+        md5_header(&self.key, &self.version, payload).to_vec()
+    }
+
+    /// The 16 characters of the hex MD5 that follow the version in encrypted 3.1 payloads. It is
+    /// calculated over the base64 of the encrypted payload, the same way as md5() otherwise.
+    pub fn md5_hex(&self, encrypted: &[u8]) -> Vec<u8> {
+        let digest = md5_digest(&self.key, &self.version, encrypted);
+        // Characters 8 to 24 of the hex
+        hex::encode(&digest[4..12]).into_bytes()
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl TuyaCipher {
+    /// The AES mode follows from the version: GCM for 3.5 and ECB for the older versions. 3.4
+    /// devices use ECB too, it is only the key that changes once the session key is negotiated.
+    fn cipher(&self) -> Cipher {
+        match self.version {
+            TuyaVersion::ThreeFive => Cipher::aes_128_gcm(),
+            _ => Cipher::aes_128_ecb(),
         }
     }
 
+    fn active_key(&self) -> &[u8] {
+        self.session_key.as_deref().unwrap_or(&self.key)
+    }
+
     pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let res = encrypt(self.cipher, &self.key, None, data)?;
+        let res = encrypt(self.cipher(), self.active_key(), None, data)?;
         match self.version {
             TuyaVersion::ThreeOne => Ok(general_purpose::STANDARD.encode(res).as_bytes().to_vec()),
-            TuyaVersion::ThreeThree => Ok(res),
+            _ => Ok(res),
         }
     }
 
     pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
-        // Different header size in version 3.1 and 3.3
-        let data = maybe_strip_header(&self.version, data);
-        // 3.1 is base64 encoded, 3.3 is not
+        // Different header size in version 3.1 and 3.3, in 3.4 the header is encrypted
+        let data = match self.version {
+            TuyaVersion::ThreeFour => data.to_vec(),
+            _ => maybe_strip_header(&self.version, data),
+        };
+        // 3.1 is base64 encoded, 3.3 and 3.4 is not
         let data = match self.version {
             TuyaVersion::ThreeOne => general_purpose::STANDARD.decode(&data)?,
-            TuyaVersion::ThreeThree => data.to_vec(),
+            _ => data.to_vec(),
         };
-        let res = decrypt(self.cipher, &self.key, None, &data)?;
+        // A wrong key shows up as a padding error from OpenSSL
+        let res = decrypt(self.cipher(), self.active_key(), None, &data)
+            .map_err(|_| ErrorKind::DecryptionFailed)?;
 
-        Ok(res.to_vec())
+        match self.version {
+            TuyaVersion::ThreeFour => Ok(maybe_strip_header(&self.version, &res)),
+            _ => Ok(res.to_vec()),
+        }
     }
 
-    pub fn md5(&self, payload: &[u8]) -> Vec<u8> {
-        let hash_line: Vec<u8> = [
-            b"data=",
-            payload,
-            b"||lpv=",
-            self.version.as_bytes(),
-            b"||",
-            self.key.as_ref(),
-        ]
-        .iter()
-        .flat_map(|bytes| bytes.iter())
-        .copied()
-        .collect();
-        let digest: [u8; 16] = md5::compute(hash_line).into();
-        digest[4..16].to_vec()
+    /// Protocol 3.5 encrypts with AES-128-GCM. The returned data is the 12 byte nonce, followed
+    /// by the encrypted data and the 16 byte authentication tag.
+    pub fn encrypt_gcm(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut iv = [0; 12];
+        rand_bytes(&mut iv)?;
+        let mut tag = [0; 16];
+        let res = encrypt_aead(
+            self.cipher(),
+            self.active_key(),
+            Some(&iv),
+            aad,
+            data,
+            &mut tag,
+        )?;
+        Ok([&iv[..], &res, &tag].concat())
+    }
+
+    /// Decrypt data laid out as by encrypt_gcm. Fails if the authentication tag does not match
+    /// the data and aad.
+    pub fn decrypt_gcm(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 28 {
+            return Err(ErrorKind::ParsingIncomplete);
+        }
+        let (iv, rest) = data.split_at(12);
+        let (data, tag) = rest.split_at(rest.len() - 16);
+        decrypt_aead(self.cipher(), self.active_key(), Some(iv), aad, data, tag)
+            .map_err(|_| ErrorKind::DecryptionFailed)
+    }
+
+    /// HMAC-SHA256 is used instead of CRC32 in protocol 3.4, both to sign frames and during the
+    /// session key negotiation.
+    pub fn hmac(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let key = PKey::hmac(self.active_key())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(data)?;
+        Ok(signer.sign_to_vec()?)
+    }
+
+    /// The session key is the xor of the two nonces exchanged during the negotiation, encrypted
+    /// with the local key. In 3.5 it is encrypted with GCM, using the start of the local nonce
+    /// as nonce.
+    pub fn derive_session_key(&self, local_nonce: &[u8], remote_nonce: &[u8]) -> Result<Vec<u8>> {
+        let xored: Vec<u8> = local_nonce
+            .iter()
+            .zip(remote_nonce.iter())
+            .map(|(l, r)| l ^ r)
+            .collect();
+        let res = match self.version {
+            TuyaVersion::ThreeFive => {
+                let mut tag = [0; 16];
+                encrypt_aead(
+                    self.cipher(),
+                    &self.key,
+                    Some(&local_nonce[..12]),
+                    &[],
+                    &xored,
+                    &mut tag,
+                )?
+            }
+            _ => encrypt(self.cipher(), &self.key, None, &xored)?,
+        };
+        Ok(res[..16].to_vec())
+    }
+}
+
+#[cfg(not(feature = "crypto"))]
+impl TuyaCipher {
+    pub fn encrypt(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(ErrorKind::CryptoDisabled)
+    }
+
+    pub fn decrypt(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(ErrorKind::CryptoDisabled)
+    }
+
+    pub fn encrypt_gcm(&self, _data: &[u8], _aad: &[u8]) -> Result<Vec<u8>> {
+        Err(ErrorKind::CryptoDisabled)
+    }
+
+    pub fn decrypt_gcm(&self, _data: &[u8], _aad: &[u8]) -> Result<Vec<u8>> {
+        Err(ErrorKind::CryptoDisabled)
+    }
+
+    pub fn hmac(&self, _data: &[u8]) -> Result<Vec<u8>> {
+        Err(ErrorKind::CryptoDisabled)
+    }
+
+    pub fn derive_session_key(&self, _local_nonce: &[u8], _remote_nonce: &[u8]) -> Result<Vec<u8>> {
+        Err(ErrorKind::CryptoDisabled)
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "crypto"))]
 mod tests {
     use super::*;
+    #[test]
+    fn cipher_mode_follows_version() {
+        for (version, expected) in [
+            (TuyaVersion::ThreeOne, Cipher::aes_128_ecb()),
+            (TuyaVersion::ThreeTwo, Cipher::aes_128_ecb()),
+            (TuyaVersion::ThreeThree, Cipher::aes_128_ecb()),
+            (TuyaVersion::ThreeFour, Cipher::aes_128_ecb()),
+            (TuyaVersion::ThreeFive, Cipher::aes_128_gcm()),
+        ] {
+            let cipher = TuyaCipher::create(b"bbe88b3f4106d354", version);
+            assert_eq!(cipher.cipher().nid(), expected.nid());
+        }
+    }
+
     #[test]
     fn maybe_strip_header_with_correct_header() {
         let cipher = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeOne);
@@ -87,6 +256,21 @@ mod tests {
         assert_eq!(maybe_strip_header(&cipher.version, &message), message)
     }
 
+    #[test]
+    fn maybe_strip_header_shorter_than_header() {
+        assert_eq!(
+            maybe_strip_header(&TuyaVersion::ThreeThree, b"3.3abc"),
+            b"3.3abc".to_vec()
+        );
+    }
+
+    #[test]
+    fn md5_hex_of_three_one_payload() {
+        let cipher = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeOne);
+        let encrypted = b"zrA8OK3r3JMiUXpXDWauNppY4Am2c8rZ6sb4Yf15MjM8n5ByDx+QWeCZtcrPqddxLrhm906bSKbQAFtT1uCp+zP5AxlqJf5d0Pp2OxyXyjg=";
+        assert_eq!(cipher.md5_hex(encrypted), b"33ed3d4a21effe90".to_vec());
+    }
+
     #[test]
     fn encrypt_message() {
         let cipher = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeOne);
@@ -155,6 +339,16 @@ mod tests {
         assert_eq!(&expected, &decrypted);
     }
 
+    #[test]
+    fn decrypt_with_wrong_key_fails_with_decryption_error() {
+        let cipher = TuyaCipher::create(b"0123456789abcdef", TuyaVersion::ThreeOne);
+        let message = b"zrA8OK3r3JMiUXpXDWauNppY4Am2c8rZ6sb4Yf15MjM8n5ByDx+QWeCZtcrPqddxLrhm906bSKbQAFtT1uCp+zP5AxlqJf5d0Pp2OxyXyjg=";
+        assert!(matches!(
+            cipher.decrypt(message),
+            Err(ErrorKind::DecryptionFailed)
+        ));
+    }
+
     #[test]
     fn decrypt_message_where_payload_is_not_json() {
         let cipher = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeOne);
@@ -164,4 +358,44 @@ mod tests {
         let decrypted = cipher.decrypt(message).unwrap();
         assert_eq!(&expected, &decrypted);
     }
+
+    #[test]
+    fn hmac_sha256() {
+        // Test case 2 from RFC 4231
+        let cipher = TuyaCipher::create(b"Jefe", TuyaVersion::ThreeFour);
+        let hmac = cipher.hmac(b"what do ya want for nothing?").unwrap();
+        assert_eq!(
+            hex::encode(hmac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn decrypt_message_with_version_threefour_strips_encrypted_header() {
+        let mut cipher = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeFour);
+        cipher.set_session_key(b"0123456789abcdef".to_vec());
+        let data = r#"{"dps":{"1":true}}"#.as_bytes();
+        let mut with_header = b"3.4".to_vec();
+        with_header.extend([0; 12]);
+        with_header.extend(data);
+
+        let encrypted = cipher.encrypt(&with_header).unwrap();
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), data.to_vec());
+        assert_eq!(cipher.session_key(), Some(&b"0123456789abcdef"[..]));
+        // The session key is used instead of the local key
+        let without_session = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeFour);
+        assert_eq!(without_session.session_key(), None);
+        assert_ne!(without_session.encrypt(&with_header).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_gcm() {
+        let cipher = TuyaCipher::create(b"bbe88b3f4106d354", TuyaVersion::ThreeFive);
+        let data = r#"{"dps":{"1":true}}"#.as_bytes();
+        let encrypted = cipher.encrypt_gcm(data, b"header").unwrap();
+        assert_eq!(encrypted.len(), 12 + data.len() + 16);
+        assert_eq!(cipher.decrypt_gcm(&encrypted, b"header").unwrap(), data);
+        // The aad is authenticated
+        assert!(cipher.decrypt_gcm(&encrypted, b"Header").is_err());
+    }
 }
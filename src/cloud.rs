@@ -0,0 +1,310 @@
+//! # TuyaCloud
+//! A minimal client for the Tuya IoT cloud, enabled with the `cloud` feature. It fetches the
+//! local keys of the devices in a cloud project, which are needed to talk to the devices locally.
+//!
+//! The credentials are the Access ID and Access Secret of a cloud project at iot.tuya.com, and the
+//! endpoint is the data center of the project, e.g. openapi.tuyaeu.com.
+//!
+//! The requests are sent over TLS from OpenSSL. With the vendored OpenSSL, point the
+//! SSL_CERT_FILE or SSL_CERT_DIR environment variables to the CA certificates of the system.
+//!
+//! ## Example
+//! ```no_run
+//! # use rust_tuyapi::{Result, cloud::TuyaCloud, TuyaDevice};
+//! # use std::net::IpAddr;
+//! # use std::str::FromStr;
+//! # fn main() -> Result<()> {
+//! let cloud = TuyaCloud::new("openapi.tuyaeu.com", "access_id", "access_secret");
+//! for device in cloud.devices()? {
+//!     println!("{} ({}): {}", device.name, device.id, device.local_key);
+//! }
+//! // The local ip of a device is not known by the cloud, see discover()
+//! let device = &cloud.devices()?[0];
+//! let tuya_device = TuyaDevice::create("3.3", Some(&device.local_key),
+//!     IpAddr::from_str("192.168.0.123").unwrap())?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::error::ErrorKind;
+use crate::Result;
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::ssl::{ErrorCode, SslConnector, SslMethod, SslStream};
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime};
+
+pub struct TuyaCloud {
+    endpoint: String,
+    access_id: String,
+    access_secret: String,
+}
+
+/// A device registered in the cloud project. The ip is the public address the device connects to
+/// the cloud from, not its address on the local network.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CloudDevice {
+    pub id: String,
+    pub name: String,
+    pub local_key: String,
+    #[serde(default)]
+    pub ip: String,
+    #[serde(default)]
+    pub category: String,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct DevicePage {
+    devices: Vec<CloudDevice>,
+    #[serde(default)]
+    has_more: bool,
+    #[serde(default)]
+    last_row_key: String,
+}
+
+impl TuyaCloud {
+    pub fn new(endpoint: &str, access_id: &str, access_secret: &str) -> TuyaCloud {
+        TuyaCloud {
+            endpoint: endpoint.to_string(),
+            access_id: access_id.to_string(),
+            access_secret: access_secret.to_string(),
+        }
+    }
+
+    /// List the devices of the cloud project, with their local keys.
+    pub fn devices(&self) -> Result<Vec<CloudDevice>> {
+        let token: Token = serde_json::from_value(self.get("/v1.0/token?grant_type=1", None)?)?;
+        let mut devices = vec![];
+        let mut last_row_key = String::new();
+        loop {
+            let path = format!(
+                "/v1.0/iot-01/associated-users/devices?last_row_key={}&size=100",
+                last_row_key
+            );
+            let page: DevicePage =
+                serde_json::from_value(self.get(&path, Some(&token.access_token))?)?;
+            devices.extend(page.devices);
+            if !page.has_more || page.last_row_key.is_empty() {
+                return Ok(devices);
+            }
+            last_row_key = page.last_row_key;
+        }
+    }
+
+    /// Send a signed GET request for path and return the result of the reply.
+    fn get(&self, path: &str, access_token: Option<&str>) -> Result<Value> {
+        let t = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis()
+            .to_string();
+        let sign = self.sign(access_token.unwrap_or_default(), &t, "GET", path)?;
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nclient_id: {}\r\nsign: {}\r\nt: {}\r\nsign_method: HMAC-SHA256\r\n",
+            path, self.endpoint, self.access_id, sign, t
+        );
+        if let Some(access_token) = access_token {
+            request += &format!("access_token: {}\r\n", access_token);
+        }
+        request += "Connection: close\r\n\r\n";
+
+        let tcp = TcpStream::connect((self.endpoint.as_str(), 443))?;
+        tcp.set_read_timeout(Some(Duration::from_secs(10)))?;
+        tcp.set_write_timeout(Some(Duration::from_secs(10)))?;
+        let connector = SslConnector::builder(SslMethod::tls())?.build();
+        let mut stream = connector
+            .connect(&self.endpoint, tcp)
+            .map_err(|e| ErrorKind::CloudError(e.to_string()))?;
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = vec![];
+        let mut buf = [0; 4096];
+        loop {
+            let bts = read_tls(&mut stream, &mut buf)?;
+            if bts == 0 {
+                let body = http_body(&response, true)?
+                    .ok_or_else(|| ErrorKind::CloudError("Incomplete HTTP response".to_string()))?;
+                return parse_reply(&body);
+            }
+            response.extend(&buf[..bts]);
+            if let Some(body) = http_body(&response, false)? {
+                return parse_reply(&body);
+            }
+        }
+    }
+
+    /// The signature of a request: the HMAC-SHA256 of the access id, access token, timestamp and
+    /// the request itself, with the access secret as key.
+    fn sign(&self, access_token: &str, t: &str, method: &str, path: &str) -> Result<String> {
+        let data = format!(
+            "{}{}{}{}",
+            self.access_id,
+            access_token,
+            t,
+            string_to_sign(method, path)?
+        );
+        let key = PKey::hmac(self.access_secret.as_bytes())?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(data.as_bytes())?;
+        Ok(hex::encode_upper(signer.sign_to_vec()?))
+    }
+}
+
+/// The request part of the signature. The requests have no body and no signed headers.
+fn string_to_sign(method: &str, path: &str) -> Result<String> {
+    let body_hash = hex::encode(hash(MessageDigest::sha256(), b"")?);
+    Ok(format!("{}\n{}\n\n{}", method, body_hash, path))
+}
+
+/// The body of an HTTP response, or None if it has not been received completely. Without a
+/// Content-Length or chunked encoding the body ends when the connection is closed.
+fn http_body(response: &[u8], closed: bool) -> Result<Option<Vec<u8>>> {
+    let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = String::from_utf8_lossy(&response[..end]).to_lowercase();
+    let body = &response[end + 4..];
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(ErrorKind::CloudError(format!("HTTP status: {}", status)));
+    }
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            match (name.trim(), value.trim()) {
+                ("transfer-encoding", "chunked") => return Ok(decode_chunked(body)),
+                ("content-length", len) => {
+                    let len: usize = len
+                        .parse()
+                        .map_err(|_| ErrorKind::CloudError(format!("Bad header: {}", line)))?;
+                    return Ok((body.len() >= len).then(|| body[..len].to_vec()));
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(closed.then(|| body.to_vec()))
+}
+
+/// Read from the TLS stream, 0 at the end of the response. The server may close without a TLS
+/// close_notify, that is the end of the response too. Timeouts and other errors are returned.
+fn read_tls(stream: &mut SslStream<TcpStream>, buf: &mut [u8]) -> Result<usize> {
+    match stream.ssl_read(buf) {
+        Ok(bts) => Ok(bts),
+        Err(e) if e.code() == ErrorCode::ZERO_RETURN => Ok(0),
+        Err(e) if e.code() == ErrorCode::SYSCALL && e.io_error().is_none() => Ok(0),
+        Err(e) => match e.into_io_error() {
+            Ok(e) => Err(ErrorKind::TcpError(e)),
+            Err(e) => Err(ErrorKind::CloudError(e.to_string())),
+        },
+    }
+}
+
+/// Join the chunks of a chunked body, None until the last chunk is received.
+fn decode_chunked(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let end = data.windows(2).position(|w| w == b"\r\n")?;
+        let size = String::from_utf8_lossy(&data[..end]);
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        let chunk = data.get(end + 2..end + 2 + size)?;
+        body.extend(chunk);
+        data = data.get(end + 4 + size..)?;
+    }
+}
+
+/// The result of a reply, or the error message of a failed request.
+fn parse_reply(body: &[u8]) -> Result<Value> {
+    let mut reply: Value = serde_json::from_slice(body)?;
+    if reply["success"] != Value::Bool(true) {
+        return Err(ErrorKind::CloudError(format!(
+            "{} ({})",
+            reply["msg"].as_str().unwrap_or("Unknown error"),
+            reply["code"]
+        )));
+    }
+    Ok(reply["result"].take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn string_to_sign_of_get_request() {
+        assert_eq!(
+            string_to_sign("GET", "/v1.0/token?grant_type=1").unwrap(),
+            "GET\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855\n\n/v1.0/token?grant_type=1"
+        );
+        let cloud = TuyaCloud::new("openapi.tuyaeu.com", "id", "secret");
+        let sign = cloud
+            .sign("", "1588925778000", "GET", "/v1.0/token")
+            .unwrap();
+        assert_eq!(sign.len(), 64);
+        assert_eq!(sign, sign.to_uppercase());
+    }
+
+    #[test]
+    fn body_with_content_length() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody";
+        assert_eq!(http_body(&response[..40], false).unwrap(), None);
+        assert_eq!(http_body(response, false).unwrap(), Some(b"body".to_vec()));
+    }
+
+    #[test]
+    fn body_with_chunked_encoding() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nbod\r\n1\r\ny\r\n0\r\n\r\n";
+        assert_eq!(http_body(&response[..55], false).unwrap(), None);
+        assert_eq!(http_body(response, false).unwrap(), Some(b"body".to_vec()));
+    }
+
+    #[test]
+    fn body_until_closed() {
+        let response = b"HTTP/1.1 200 OK\r\n\r\nbody";
+        assert_eq!(http_body(response, false).unwrap(), None);
+        assert_eq!(http_body(response, true).unwrap(), Some(b"body".to_vec()));
+        let response = b"HTTP/1.1 500 Internal Server Error\r\n\r\n";
+        assert!(matches!(
+            http_body(response, true),
+            Err(ErrorKind::CloudError(_))
+        ));
+    }
+
+    #[test]
+    fn parse_device_page() {
+        let body = br#"{"success":true,"t":1588925778000,"result":{"has_more":false,"last_row_key":"","devices":[
+            {"id":"002004265ccf7fb1b659","name":"Socket","local_key":"bbe88b3f4106d354","ip":"1.2.3.4","category":"cz","online":true}]}}"#;
+        let page: DevicePage = serde_json::from_value(parse_reply(body).unwrap()).unwrap();
+        assert!(!page.has_more);
+        assert_eq!(
+            page.devices,
+            vec![CloudDevice {
+                id: "002004265ccf7fb1b659".to_string(),
+                name: "Socket".to_string(),
+                local_key: "bbe88b3f4106d354".to_string(),
+                ip: "1.2.3.4".to_string(),
+                category: "cz".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn failed_request_is_cloud_error() {
+        let body = br#"{"success":false,"code":1004,"msg":"sign invalid"}"#;
+        match parse_reply(body) {
+            Err(ErrorKind::CloudError(msg)) => assert_eq!(msg, "sign invalid (1004)"),
+            _ => panic!("Expected a CloudError"),
+        }
+    }
+}
@@ -1,3 +1,14 @@
+//! # CRC
+//! The checksum of the frames of protocol 3.1 to 3.3. It is the common CRC-32 (ISO-HDLC), with the
+//! polynomial 0x04C11DB7 (0xEDB88320 reflected), an initial value and final xor of 0xFFFFFFFF,
+//! the same as zlib.crc32 used by tinytuya. It covers the frame from the prefix up to the
+//! checksum itself, and is sent big endian.
+
+/// The CRC-32 of data, as used in the Tuya frames.
+pub fn crc(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
 #[test]
 fn test_crc_calculation() {
     let crcval = crc32fast::hash(b"Hello World");
@@ -7,3 +18,12 @@ fn test_crc_calculation() {
     let crcval = crc32fast::hash(b"{devId: '002004265ccf7fb1b659', dps: {1: true, 2: 0}}");
     assert_eq!(format!("{:x}", crcval), "a524febe");
 }
+
+#[test]
+fn test_crc_of_tuya_frames() {
+    // A heart beat frame, the CRC covers everything before it
+    let frame = hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+    assert_eq!(crc(&frame[..20]), 0xb051ab03);
+    let frame = hex::decode("000055aa000000000000000a0000000c0000000089dc97c60000aa55").unwrap();
+    assert_eq!(crc(&frame[..20]), 0x89dc97c6);
+}
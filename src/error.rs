@@ -1,3 +1,4 @@
+#[cfg(feature = "crypto")]
 use openssl::error::ErrorStack;
 use std::io;
 use std::str::Utf8Error;
@@ -7,7 +8,9 @@ use thiserror::Error;
 #[error("{0}")]
 pub enum ErrorKind {
     Base64DecodeError(#[from] base64::DecodeError),
+    HexError(#[from] hex::FromHexError),
     JsonError(#[from] serde_json::error::Error),
+    #[cfg(feature = "crypto")]
     OpenSSLError(#[from] ErrorStack),
     SystemTimeError(#[from] std::time::SystemTimeError),
     TcpError(#[from] io::Error),
@@ -21,18 +24,71 @@ pub enum ErrorKind {
     CanNotEncodeMessageWithoutCommand,
     #[error("No CommandType was supplied in message")]
     CommandTypeMissing,
+    #[error("Encryption is not available, the crate is built without the crypto feature")]
+    CryptoDisabled,
+    #[error("Decryption failed, is the local key correct?")]
+    DecryptionFailed,
+    #[error("The device replied with error code {code}: {message}")]
+    DeviceError {
+        code: u8,
+        message: String,
+    },
+    #[error("The device id {0:?} is not valid, device ids are 10 to 32 letters and digits")]
+    InvalidDevId(String),
+    #[error("The divisor of dp {0} is 0")]
+    InvalidScale(String),
+    #[error("A {0:?} request needs a Payload::Struct")]
+    InvalidPayloadForCommand(crate::mesparse::CommandType),
     #[error("Error: CRC mismatch")]
     CRCError,
     #[error("The key length is {0}, should be 16")]
     KeyLength(usize),
+    #[error("The key looks like hex but has {0} characters, a hex key should have 32")]
+    HexKeyLength(usize),
     #[error("the tuyadevice is not created with a socket address. can not set object")]
     MissingAddressError,
+    #[error("The tuyadevice is not created with a device id")]
+    MissingDevIdError,
     #[error("Data was incomplete. Error while parsing the received data")]
     ParsingIncomplete,
+    #[error("Timed out waiting for the device")]
+    Timeout,
+    #[error("The device refused the connection, is it powered on?")]
+    ConnectionRefused,
+    #[error("The device reset the connection after the request was written")]
+    ConnectionResetAfterWrite,
+    #[error("The device closed the connection without replying")]
+    EmptyReply,
+    #[error("Can not bind to {0}, is it the address of a local interface? {1}")]
+    BindError(std::net::SocketAddr, #[source] io::Error),
+    #[error("Version {0} needs the local key of the device, without it only the UDP broadcasts can be decrypted")]
+    KeyRequiredForVersion(crate::mesparse::TuyaVersion),
     #[error("Bad read from TcpStream")]
     BadTcpRead,
     #[error("Bad read from UdpSocket")]
     BadUdpRead,
+    #[error("Expected a reply with sequence number {expected}, got {got}")]
+    SequenceMismatch {
+        expected: u32,
+        got: u32,
+    },
+    #[error("Request to the Tuya cloud failed: {0}")]
+    CloudError(String),
+    #[error("The custom transport is closed and can not be reopened")]
+    CustomTransportClosed,
+    #[error("The session key negotiation with the device failed")]
+    SessionKeyNegotiationError,
+    #[error("None of the protocol versions gave a valid reply from the device")]
+    VersionProbeFailed,
     #[error("The given version {0}.{1} is not valid")]
     VersionError(String, String),
 }
+
+impl From<nom::Err<nom::error::Error<&[u8]>>> for ErrorKind {
+    fn from(err: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        match err {
+            nom::Err::Incomplete(_) => ErrorKind::ParsingIncomplete,
+            nom::Err::Error(e) | nom::Err::Failure(e) => ErrorKind::ParseError(e.code),
+        }
+    }
+}
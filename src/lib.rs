@@ -1,33 +1,22 @@
 //! # Rust Tuyapi
 //! This library can be used to interact with Tuya/Smart Home devices. It utilizes the Tuya
-//! protocol version 3.1 and 3.3 to send and receive messages from the devices.
+//! protocol version 3.1, 3.3, 3.4 and 3.5 to send and receive messages from the devices.
 //!
 //! ## Example
 //! This shows how to turn on a wall socket.
 //! ```no_run
 //! # extern crate rust_tuyapi;
-//! # use rust_tuyapi::{Payload, Result, PayloadStruct,tuyadevice::TuyaDevice};
+//! # use rust_tuyapi::{Result, PayloadStruct,tuyadevice::TuyaDevice};
 //! # use std::net::IpAddr;
 //! # use std::str::FromStr;
-//! # use std::collections::HashMap;
-//! # use std::time::SystemTime;
-//! # use serde_json::json;
 //! # fn main() -> Result<()> {
-//! // The dps value is device specific, this socket turns on with key "1"
-//! let mut dps = HashMap::new();
-//! dps.insert("1".to_string(), json!(true));
-//! let current_time = SystemTime::now()
-//!     .duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as u32;
-//!
-//! // Create the payload to be sent, this will be serialized to the JSON format
-//! let payload = Payload::Struct(PayloadStruct{
-//!        dev_id: "123456789abcdef".to_string(),
-//!        gw_id: Some("123456789abcdef".to_string()),
-//!        uid: None,
-//!        t: Some(current_time),
-//!        dp_id: None,
-//!        dps: Some(dps),
-//!        });
+//! // Create the payload to be sent, this will be serialized to the JSON format. The dps value
+//! // is device specific, this socket turns on with key "1".
+//! let payload = PayloadStruct::builder("123456789abcdef")
+//!     .gw_id("123456789abcdef")
+//!     .timestamp_now()
+//!     .dp("1", true)
+//!     .build();
 //! // Create a TuyaDevice, this is the type used to set/get status to/from a Tuya compatible
 //! // device.
 //! let tuya_device = TuyaDevice::create("ver3.3", Some("fedcba987654321"),
@@ -39,10 +28,17 @@
 //! # Ok(())
 //! # }
 //! ```
+#[cfg(feature = "async")]
+pub mod asynctuyadevice;
 mod cipher;
-mod crc;
+#[cfg(feature = "cloud")]
+pub mod cloud;
+pub mod crc;
 pub mod error;
 pub mod mesparse;
+pub mod pool;
+pub mod record;
+pub mod scale;
 pub mod transports;
 pub mod tuyadevice;
 
@@ -51,22 +47,36 @@ extern crate num_derive;
 #[macro_use]
 extern crate lazy_static;
 
-use serde::{Deserialize, Serialize};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::SystemTime;
 
 use crate::error::ErrorKind;
 use std::convert::TryInto;
 
-pub use transports::Transport;
-pub use tuyadevice::TuyaDevice;
+#[cfg(feature = "async")]
+pub use asynctuyadevice::AsyncTuyaDevice;
+pub use pool::{PooledDevice, TuyaDevicePool};
+#[cfg(feature = "async")]
+pub use transports::AsyncTuyaTransport;
+pub use transports::{Transport, TransportOptions, TuyaTransport};
+pub use tuyadevice::{Session, TuyaDevice, TuyaDeviceBuilder, Watch};
 
 pub type Result<T> = std::result::Result<T, ErrorKind>;
 /// The Payload enum represents a payload sent to, and recevied from the Tuya devices. It might be
-/// a struct (ser/de from json) or a plain string.
-#[derive(Debug, Clone, PartialEq)]
+/// a struct (ser/de from json) or a plain string. It is serialized as the json object or string.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+// Boxing the PayloadStruct would break matching on Payload::Struct
+#[allow(clippy::large_enum_variant)]
 pub enum Payload {
     Struct(PayloadStruct),
     String(String),
@@ -96,15 +106,75 @@ impl Payload {
         dp_id: Option<DpId>,
         dps: Option<HashMap<String, serde_json::Value>>,
     ) -> Payload {
+        warn_invalid_dev_id(&dev_id);
         Payload::Struct(PayloadStruct {
             dev_id,
             gw_id,
+            cid: None,
             uid,
             t,
+            s: None,
             dp_id: dp_id.map(DpId::get_ids),
             dps,
+            extra: HashMap::new(),
+        })
+    }
+
+    /// The payload to read the status of a device with get(). It has an empty dps and the
+    /// current timestamp.
+    pub fn query(dev_id: &str, gw_id: &str) -> Payload {
+        Payload::Struct(PayloadStruct {
+            dev_id: dev_id.to_string(),
+            gw_id: Some(gw_id.to_string()),
+            cid: None,
+            uid: None,
+            t: now(),
+            s: None,
+            dp_id: None,
+            dps: Some(HashMap::new()),
+            extra: HashMap::new(),
         })
     }
+
+    /// Load a payload saved with to_json_file(), or any file with the payload as JSON, e.g.
+    /// `{"devId":"002004265ccf7fb1b659","dps":{"1":true}}`.
+    pub fn from_json_file(path: impl AsRef<Path>) -> Result<Payload> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Save the payload as pretty printed JSON, in the format sent to the device.
+    pub fn to_json_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(file.flush()?)
+    }
+}
+
+/// Seconds since the unix epoch, which is what devices expect in t.
+fn now() -> Option<u32> {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|now| now.as_secs() as u32)
+        .ok()
+}
+
+impl From<&str> for Payload {
+    fn from(s: &str) -> Payload {
+        Payload::String(s.to_string())
+    }
+}
+
+impl From<String> for Payload {
+    fn from(s: String) -> Payload {
+        Payload::String(s)
+    }
+}
+
+impl From<PayloadStruct> for Payload {
+    fn from(s: PayloadStruct) -> Payload {
+        Payload::Struct(s)
+    }
 }
 
 impl Display for Payload {
@@ -116,6 +186,94 @@ impl Display for Payload {
     }
 }
 
+/// A dps value. Devices use booleans, integers, strings and sometimes floats. Anything else, e.g.
+/// the nested objects of some devices or integers above i64::MAX, is kept in Raw, so that every
+/// json value converts back unchanged. It is (de)serialized as the plain json value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "serde_json::Value", into = "serde_json::Value")]
+pub enum DpsValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Raw(serde_json::Value),
+}
+
+impl From<serde_json::Value> for DpsValue {
+    fn from(value: serde_json::Value) -> DpsValue {
+        match value {
+            serde_json::Value::Bool(b) => DpsValue::Bool(b),
+            serde_json::Value::Number(ref n) if n.is_i64() => DpsValue::Int(n.as_i64().unwrap()),
+            serde_json::Value::Number(ref n) if n.is_f64() => DpsValue::Float(n.as_f64().unwrap()),
+            serde_json::Value::String(s) => DpsValue::Str(s),
+            value => DpsValue::Raw(value),
+        }
+    }
+}
+
+impl From<DpsValue> for serde_json::Value {
+    fn from(value: DpsValue) -> serde_json::Value {
+        match value {
+            DpsValue::Bool(b) => b.into(),
+            DpsValue::Int(i) => i.into(),
+            DpsValue::Float(f) => f.into(),
+            DpsValue::Str(s) => s.into(),
+            DpsValue::Raw(value) => value,
+        }
+    }
+}
+
+impl From<bool> for DpsValue {
+    fn from(b: bool) -> DpsValue {
+        DpsValue::Bool(b)
+    }
+}
+
+macro_rules! dps_value_from_int {
+    ($($int:ty),*) => {
+        $(impl From<$int> for DpsValue {
+            fn from(i: $int) -> DpsValue {
+                DpsValue::Int(i.into())
+            }
+        })*
+    };
+}
+
+dps_value_from_int!(i8, i16, i32, i64, u8, u16, u32);
+
+impl From<u64> for DpsValue {
+    fn from(u: u64) -> DpsValue {
+        match i64::try_from(u) {
+            Ok(i) => DpsValue::Int(i),
+            Err(_) => DpsValue::Raw(u.into()),
+        }
+    }
+}
+
+impl From<f32> for DpsValue {
+    fn from(f: f32) -> DpsValue {
+        DpsValue::Float(f.into())
+    }
+}
+
+impl From<f64> for DpsValue {
+    fn from(f: f64) -> DpsValue {
+        DpsValue::Float(f)
+    }
+}
+
+impl From<&str> for DpsValue {
+    fn from(s: &str) -> DpsValue {
+        DpsValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for DpsValue {
+    fn from(s: String) -> DpsValue {
+        DpsValue::Str(s)
+    }
+}
+
 /// The PayloadStruct is Serialized to json and sent to the device. The dps field contains the
 /// actual commands to set and are device specific.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
@@ -124,14 +282,206 @@ pub struct PayloadStruct {
     pub dev_id: String,
     #[serde(rename = "gwId", skip_serializing_if = "Option::is_none")]
     pub gw_id: Option<String>,
+    /// The id of a sub-device (e.g. Zigbee or BLE) behind a gateway. The gateway is the device
+    /// that is connected to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cid: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub uid: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub t: Option<u32>,
+    /// A sequence number that some 3.1 devices include in their payloads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub s: Option<u32>,
     #[serde(rename = "dpId", skip_serializing_if = "Option::is_none")]
     pub dp_id: Option<Vec<u8>>,
+    /// Integer values are kept exact in the range of i64 and u64, e.g. the energy counters of
+    /// meters. Larger integers are parsed as f64 and lose precision above 2^53.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub dps: Option<HashMap<String, serde_json::Value>>,
+    /// Fields this struct does not model, e.g. reqType of some firmwares. They are kept, so
+    /// that a parsed payload is serialized the same way again.
+    #[serde(flatten)]
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl PayloadStruct {
+    /// Get a dps value deserialized to T. Returns None if the key is missing or the value can
+    /// not be deserialized to T.
+    pub fn dp<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.dps
+            .as_ref()?
+            .get(key)
+            .and_then(|value| serde_json::from_value(value.clone()).ok())
+    }
+
+    /// The timestamp t, in seconds since the unix epoch. In a reply it is the time of the device,
+    /// which can be compared to the local time to find the clock drift of the device. Some
+    /// devices reject commands with a t too far from their own clock, so commands should set t
+    /// close to the device time.
+    pub fn timestamp(&self) -> Option<u32> {
+        self.t
+    }
+
+    /// The dps with the dp ids replaced by the names in the schema. Dps without a name in the
+    /// schema keep their id.
+    pub fn named_dps(&self, schema: &DpsSchema) -> HashMap<String, serde_json::Value> {
+        self.dps
+            .iter()
+            .flatten()
+            .map(|(id, value)| {
+                let name = schema.name(id).unwrap_or(id);
+                (name.to_string(), value.clone())
+            })
+            .collect()
+    }
+
+    /// Get a dps value as a DpsValue. Returns None if the key is missing.
+    pub fn dp_value(&self, key: &str) -> Option<DpsValue> {
+        Some(self.dps.as_ref()?.get(key)?.clone().into())
+    }
+
+    /// All dps as DpsValues, empty if the payload has no dps.
+    pub fn dps_values(&self) -> HashMap<String, DpsValue> {
+        self.dps
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect()
+    }
+
+    pub fn dp_bool(&self, key: &str) -> Option<bool> {
+        self.dps.as_ref()?.get(key)?.as_bool()
+    }
+
+    pub fn dp_u64(&self, key: &str) -> Option<u64> {
+        self.dps.as_ref()?.get(key)?.as_u64()
+    }
+
+    pub fn dp_i64(&self, key: &str) -> Option<i64> {
+        self.dps.as_ref()?.get(key)?.as_i64()
+    }
+
+    pub fn dp_str(&self, key: &str) -> Option<&str> {
+        self.dps.as_ref()?.get(key)?.as_str()
+    }
+
+    pub fn builder(dev_id: &str) -> PayloadBuilder {
+        PayloadBuilder {
+            payload: PayloadStruct {
+                dev_id: dev_id.to_string(),
+                gw_id: None,
+                cid: None,
+                uid: None,
+                t: None,
+                s: None,
+                dp_id: None,
+                dps: None,
+                extra: HashMap::new(),
+            },
+        }
+    }
+}
+
+/// Maps the dp ids of a device to names, e.g. "1" to "switch" and "20" to "brightness". Created
+/// from a HashMap, or from a json object like `{"1": "switch", "20": "brightness"}`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct DpsSchema {
+    names: HashMap<String, String>,
+}
+
+impl DpsSchema {
+    pub fn from_json(json: &str) -> Result<DpsSchema> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn name(&self, dp: &str) -> Option<&str> {
+        self.names.get(dp).map(String::as_str)
+    }
+}
+
+impl From<HashMap<String, String>> for DpsSchema {
+    fn from(names: HashMap<String, String>) -> DpsSchema {
+        DpsSchema { names }
+    }
+}
+
+/// Builder for a Payload::Struct, created with PayloadStruct::builder().
+pub struct PayloadBuilder {
+    payload: PayloadStruct,
+}
+
+impl PayloadBuilder {
+    /// Add a dps entry. The key and value are device specific. The value is anything that
+    /// converts to a DpsValue, e.g. a bool, an integer, a string or a serde_json::Value.
+    pub fn dp(mut self, key: &str, value: impl Into<DpsValue>) -> PayloadBuilder {
+        self.payload
+            .dps
+            .get_or_insert_with(HashMap::new)
+            .insert(key.to_string(), value.into().into());
+        self
+    }
+
+    pub fn gw_id(mut self, gw_id: &str) -> PayloadBuilder {
+        self.payload.gw_id = Some(gw_id.to_string());
+        self
+    }
+
+    /// Address a sub-device behind the gateway.
+    pub fn cid(mut self, cid: &str) -> PayloadBuilder {
+        self.payload.cid = Some(cid.to_string());
+        self
+    }
+
+    pub fn uid(mut self, uid: &str) -> PayloadBuilder {
+        self.payload.uid = Some(uid.to_string());
+        self
+    }
+
+    /// Set t to the current time in seconds since the unix epoch.
+    pub fn timestamp_now(mut self) -> PayloadBuilder {
+        self.payload.t = now();
+        self
+    }
+
+    /// Set the sequence number s, for 3.1 devices that expect it.
+    pub fn s(mut self, s: u32) -> PayloadBuilder {
+        self.payload.s = Some(s);
+        self
+    }
+
+    /// Build the payload. A dev_id that fails validate_dev_id() is logged as a warning, use
+    /// try_build() to reject it instead.
+    pub fn build(self) -> Payload {
+        warn_invalid_dev_id(&self.payload.dev_id);
+        Payload::Struct(self.payload)
+    }
+
+    /// Build the payload, or fail with ErrorKind::InvalidDevId if the dev_id fails
+    /// validate_dev_id().
+    pub fn try_build(self) -> Result<Payload> {
+        validate_dev_id(&self.payload.dev_id)?;
+        Ok(Payload::Struct(self.payload))
+    }
+}
+
+/// Device ids are usually 20 to 22 letters and digits. Only ids that can not be valid are
+/// rejected, i.e. ids shorter than 10 or longer than 32 characters or with other characters than
+/// ASCII letters and digits, e.g. a typo or a pasted id with spaces. A device given such an id
+/// replies with an error like "gw id invalid".
+pub fn validate_dev_id(dev_id: &str) -> Result<()> {
+    if (10..=32).contains(&dev_id.len()) && dev_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(())
+    } else {
+        Err(ErrorKind::InvalidDevId(dev_id.to_string()))
+    }
+}
+
+fn warn_invalid_dev_id(dev_id: &str) {
+    if let Err(e) = validate_dev_id(dev_id) {
+        warn!("{}", e);
+    }
 }
 
 /// This trait is implemented to allow truncated logging of secret data.
@@ -147,10 +497,41 @@ pub trait Truncate {
     }
 }
 
+/// Ids, addresses and packet dumps are only logged in full when the TUYA_FULL_DISPLAY
+/// environment variable is set.
+pub(crate) fn full_display() -> bool {
+    std::env::var("TUYA_FULL_DISPLAY").is_ok()
+}
+
+/// The device address for logging. Only the last part of the ip is shown, unless full display
+/// is enabled.
+pub(crate) fn log_addr(addr: &SocketAddr) -> String {
+    if full_display() {
+        return addr.to_string();
+    }
+    let ip = addr.ip().to_string();
+    let last = ip.rsplit(['.', ':']).next().unwrap_or_default();
+    format!("...{}:{}", last, addr.port())
+}
+
+/// Hex dump of a packet for logging. The packets may contain enough to recover the local key,
+/// so only the length is shown unless full display is enabled.
+pub(crate) fn log_hex(buf: &[u8]) -> String {
+    if full_display() {
+        hex::encode(buf)
+    } else {
+        format!("<{} bytes, set TUYA_FULL_DISPLAY to show>", buf.len())
+    }
+}
+
 impl TryFrom<Vec<u8>> for Payload {
     type Error = ErrorKind;
 
     fn try_from(vec: Vec<u8>) -> Result<Self> {
+        // Heartbeats and acks have no payload at all
+        if vec.is_empty() {
+            return Ok(Payload::String(String::new()));
+        }
         match serde_json::from_slice(&vec)? {
             serde_json::Value::String(s) => Ok(Payload::String(s)),
             value => Ok(Payload::Struct(serde_json::from_value(value)?)),
@@ -160,9 +541,18 @@ impl TryFrom<Vec<u8>> for Payload {
 impl TryInto<Vec<u8>> for Payload {
     type Error = ErrorKind;
 
+    fn try_into(self) -> Result<Vec<u8>> {
+        (&self).try_into()
+    }
+}
+
+/// Serialize without consuming, or cloning, the Payload.
+impl TryInto<Vec<u8>> for &Payload {
+    type Error = ErrorKind;
+
     fn try_into(self) -> Result<Vec<u8>> {
         match self {
-            Payload::Struct(s) => Ok(serde_json::to_vec(&s)?),
+            Payload::Struct(s) => Ok(serde_json::to_vec(s)?),
             Payload::String(s) => Ok(s.as_bytes().to_vec()),
         }
     }
@@ -176,21 +566,444 @@ impl Truncate for PayloadStruct {
                 .gw_id
                 .as_ref()
                 .map(|gwid| String::from("...") + Self::truncate_str(gwid)),
+            cid: self
+                .cid
+                .as_ref()
+                .map(|cid| String::from("...") + Self::truncate_str(cid)),
             t: self.t,
+            s: self.s,
             dp_id: self.dp_id.clone(),
-            uid: self.uid.clone(),
-            dps: self.dps.clone(),
+            uid: self
+                .uid
+                .as_ref()
+                .map(|uid| String::from("...") + Self::truncate_str(uid)),
+            // String values may be secrets, e.g. door codes
+            dps: self.dps.as_ref().map(|dps| {
+                dps.iter()
+                    .map(|(dp, value)| match value {
+                        serde_json::Value::String(_) => (dp.clone(), "***".into()),
+                        _ => (dp.clone(), value.clone()),
+                    })
+                    .collect()
+            }),
+            extra: self.extra.clone(),
         }
     }
 }
 
+impl PayloadStruct {
+    /// The payload as JSON with all ids and dps, whether or not TUYA_FULL_DISPLAY is set.
+    pub fn display_full(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// The payload as JSON with the ids and string dps hidden, see Truncate, whether or not
+    /// TUYA_FULL_DISPLAY is set.
+    pub fn display_redacted(&self) -> String {
+        serde_json::to_string(&self.truncate()).unwrap()
+    }
+}
+
+/// Redacted like display_redacted(), or in full like display_full() when the TUYA_FULL_DISPLAY
+/// environment variable is set.
 impl Display for PayloadStruct {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let full_display = std::env::var("TUYA_FULL_DISPLAY").map_or_else(|_| false, |_| true);
-        if full_display {
-            write!(f, "{}", serde_json::to_string(self).unwrap())
+        if full_display() {
+            write!(f, "{}", self.display_full())
         } else {
-            write!(f, "{}", serde_json::to_string(&self.truncate()).unwrap())
+            write!(f, "{}", self.display_redacted())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn build_payload() {
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .gw_id("002004265ccf7fb1b659")
+            .uid("someuid")
+            .dp("1", true)
+            .dp("2", 255)
+            .dp("3", "white")
+            .build();
+        let mut dps = HashMap::new();
+        dps.insert("1".to_string(), json!(true));
+        dps.insert("2".to_string(), json!(255));
+        dps.insert("3".to_string(), json!("white"));
+        let expected = Payload::Struct(PayloadStruct {
+            dev_id: "002004265ccf7fb1b659".to_string(),
+            gw_id: Some("002004265ccf7fb1b659".to_string()),
+            cid: None,
+            uid: Some("someuid".to_string()),
+            t: None,
+            s: None,
+            dp_id: None,
+            dps: Some(dps),
+            extra: HashMap::new(),
+        });
+        assert_eq!(payload, expected);
+    }
+
+    #[test]
+    fn serialize_cid_only_when_set() {
+        let gateway: Vec<u8> = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .build()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            gateway,
+            br#"{"devId":"002004265ccf7fb1b659","dps":{"1":true}}"#
+        );
+
+        let sub_device: Vec<u8> = PayloadStruct::builder("002004265ccf7fb1b659")
+            .cid("a4c1380fcb7e42a2")
+            .dp("1", true)
+            .build()
+            .try_into()
+            .unwrap();
+        assert_eq!(
+            sub_device,
+            br#"{"devId":"002004265ccf7fb1b659","cid":"a4c1380fcb7e42a2","dps":{"1":true}}"#
+        );
+    }
+
+    #[test]
+    fn log_addr_hides_ip() {
+        // Other tests do not set TUYA_FULL_DISPLAY
+        let addr: SocketAddr = "192.168.0.123:6668".parse().unwrap();
+        assert_eq!(log_addr(&addr), "...123:6668");
+        assert_eq!(
+            log_hex(&[0, 1, 2]),
+            "<3 bytes, set TUYA_FULL_DISPLAY to show>"
+        );
+    }
+
+    #[test]
+    fn serialize_borrowed_payload() {
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .gw_id("002004265ccf7fb1b659")
+            .dp("1", true)
+            .build();
+        let borrowed: Vec<u8> = (&payload).try_into().unwrap();
+        let owned: Vec<u8> = payload.try_into().unwrap();
+        assert_eq!(borrowed, owned);
+    }
+
+    #[test]
+    fn payload_from_strings_and_struct() {
+        assert_eq!(Payload::from(""), Payload::String(String::new()));
+        assert_eq!(
+            Payload::from("{}".to_string()),
+            Payload::String("{}".to_string())
+        );
+        let reply: PayloadStruct =
+            serde_json::from_str(r#"{"devId":"002004265ccf7fb1b659"}"#).unwrap();
+        assert_eq!(Payload::from(reply.clone()), Payload::Struct(reply));
+    }
+
+    #[test]
+    fn empty_vec_is_empty_string_payload() {
+        let payload = Payload::try_from(vec![]).unwrap();
+        assert_eq!(payload, Payload::String(String::new()));
+    }
+
+    #[test]
+    fn build_payload_with_timestamp() {
+        let before = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        match PayloadStruct::builder("002004265ccf7fb1b659")
+            .timestamp_now()
+            .build()
+        {
+            Payload::Struct(s) => {
+                assert!(s.t.unwrap() >= before);
+                assert_eq!(s.dps, None);
+            }
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        }
+    }
+
+    #[test]
+    fn timestamp_of_reply() {
+        let reply: PayloadStruct = serde_json::from_str(
+            r#"{"devId":"002004265ccf7fb1b659","dps":{"1":false},"t":1529442366}"#,
+        )
+        .unwrap();
+        assert_eq!(reply.timestamp(), Some(1529442366));
+    }
+
+    #[test]
+    fn named_dps_from_schema() {
+        let schema = DpsSchema::from_json(r#"{"1": "switch", "20": "brightness"}"#).unwrap();
+        let payload = match PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .dp("20", 255)
+            .dp("21", "white")
+            .build()
+        {
+            Payload::Struct(s) => s,
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        };
+        let mut expected = HashMap::new();
+        expected.insert("switch".to_string(), json!(true));
+        expected.insert("brightness".to_string(), json!(255));
+        expected.insert("21".to_string(), json!("white"));
+        assert_eq!(payload.named_dps(&schema), expected);
+
+        let names: HashMap<String, String> = [("1".to_string(), "switch".to_string())].into();
+        assert_eq!(DpsSchema::from(names).name("1"), Some("switch"));
+    }
+
+    #[test]
+    fn truncate_hides_ids_and_string_dps() {
+        let payload = PayloadStruct {
+            dev_id: "002004265ccf7fb1b659".to_string(),
+            gw_id: Some("bf1a2b3c4d5e6f7a8b9c".to_string()),
+            cid: Some("a4c1389f0e2d".to_string()),
+            uid: None,
+            t: None,
+            s: None,
+            dp_id: None,
+            dps: Some(HashMap::from([
+                ("1".to_string(), json!(true)),
+                ("2".to_string(), json!("123456")),
+            ])),
+            extra: HashMap::new(),
+        };
+        let truncated = payload.truncate();
+        assert_eq!(truncated.dev_id, "...b1b659");
+        assert_eq!(truncated.gw_id.as_deref(), Some("...7a8b9c"));
+        assert_eq!(truncated.cid.as_deref(), Some("...9f0e2d"));
+        assert_eq!(truncated.dp_bool("1"), Some(true));
+        assert_eq!(truncated.dps.unwrap()["2"], json!("***"));
+    }
+
+    #[test]
+    fn large_integer_dps_stay_exact() {
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", u64::MAX)
+            .dp("2", (1_u64 << 53) + 1)
+            .dp("3", i64::MIN)
+            .build();
+        let bytes: Vec<u8> = payload.try_into().unwrap();
+        let parsed = match Payload::try_from(bytes).unwrap() {
+            Payload::Struct(s) => s,
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        };
+        assert_eq!(parsed.dp_u64("1"), Some(u64::MAX));
+        assert_eq!(parsed.dp_u64("2"), Some((1 << 53) + 1));
+        assert_eq!(parsed.dp_i64("3"), Some(i64::MIN));
+        assert!(parsed.dps.unwrap().values().all(|v| !v.is_f64()));
+    }
+
+    #[test]
+    fn dps_values_round_trip() {
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .dp("2", 255)
+            .dp("3", 21.5)
+            .dp("4", "white")
+            .dp("5", u64::MAX)
+            .dp("6", json!({"h": 120, "s": 1000}))
+            .dp("7", DpsValue::Int(-1))
+            .build();
+        let Payload::Struct(payload) = payload else {
+            panic!("built a string payload");
+        };
+        assert_eq!(payload.dp_value("1"), Some(DpsValue::Bool(true)));
+        assert_eq!(payload.dp_value("2"), Some(DpsValue::Int(255)));
+        assert_eq!(payload.dp_value("3"), Some(DpsValue::Float(21.5)));
+        assert_eq!(
+            payload.dp_value("4"),
+            Some(DpsValue::Str("white".to_string()))
+        );
+        assert_eq!(payload.dp_value("5"), Some(DpsValue::Raw(json!(u64::MAX))));
+        assert_eq!(
+            payload.dp_value("6"),
+            Some(DpsValue::Raw(json!({"h": 120, "s": 1000})))
+        );
+        assert_eq!(payload.dp_value("7"), Some(DpsValue::Int(-1)));
+        assert_eq!(payload.dp_value("8"), None);
+        assert_eq!(payload.dps_values().len(), 7);
+
+        let json = serde_json::to_string(&payload.dps_values()).unwrap();
+        let parsed: HashMap<String, DpsValue> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, payload.dps_values());
+        assert_eq!(
+            serde_json::to_value(DpsValue::Str("white".to_string())).unwrap(),
+            json!("white")
+        );
+    }
+
+    #[test]
+    fn sequence_field_round_trip() {
+        // The payload of the 3.1 cipher tests
+        let json =
+            r#"{"devId":"002004265ccf7fb1b659","dps":{"1":false,"2":0},"t":1529442366,"s":8}"#;
+        let payload: PayloadStruct = serde_json::from_str(json).unwrap();
+        assert_eq!(payload.s, Some(8));
+        assert_eq!(payload.t, Some(1529442366));
+        assert!(payload.extra.is_empty());
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::from_str::<serde_json::Value>(json).unwrap()
+        );
+        let built = PayloadStruct::builder("002004265ccf7fb1b659").s(8).build();
+        assert_eq!(
+            serde_json::to_string(&built).unwrap(),
+            r#"{"devId":"002004265ccf7fb1b659","s":8}"#
+        );
+    }
+
+    #[test]
+    fn json_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("tuyapi-payload-{}.json", std::process::id()));
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .gw_id("002004265ccf7fb1b659")
+            .dp("1", true)
+            .dp("2", 255)
+            .build();
+        payload.to_json_file(&path).unwrap();
+        let loaded = Payload::from_json_file(&path).unwrap();
+        std::fs::write(&path, r#"{"dps":{"1":true}}"#).unwrap();
+        let missing_dev_id = Payload::from_json_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, payload);
+        assert!(matches!(missing_dev_id, Err(ErrorKind::JsonError(_))));
+    }
+
+    #[test]
+    fn display_full_ignores_env() {
+        let payload = PayloadStruct {
+            dev_id: "002004265ccf7fb1b659".to_string(),
+            gw_id: None,
+            cid: None,
+            uid: None,
+            t: None,
+            s: None,
+            dp_id: None,
+            dps: None,
+            extra: HashMap::new(),
+        };
+        // Other tests do not set TUYA_FULL_DISPLAY, so Display redacts
+        assert_eq!(payload.to_string(), payload.display_redacted());
+        assert_eq!(
+            payload.display_full(),
+            r#"{"devId":"002004265ccf7fb1b659"}"#
+        );
+        assert!(!payload.display_redacted().contains("002004265ccf7fb1b659"));
+    }
+
+    #[test]
+    fn validate_dev_ids() {
+        assert!(validate_dev_id("002004265ccf7fb1b659").is_ok());
+        assert!(validate_dev_id("bf0123456789abcdefghij").is_ok());
+        assert!(validate_dev_id("123456789abcdef").is_ok());
+        for invalid in [
+            "",
+            "0020042",
+            "002004265ccf 7fb1b659",
+            "002004265ccf7fb1b659\n",
+        ] {
+            assert!(matches!(
+                validate_dev_id(invalid),
+                Err(ErrorKind::InvalidDevId(id)) if id == invalid
+            ));
         }
+        assert!(PayloadStruct::builder("00200426-5ccf7fb1b659")
+            .try_build()
+            .is_err());
+        assert!(PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .try_build()
+            .is_ok());
+    }
+
+    #[test]
+    fn unknown_fields_are_kept() {
+        let json =
+            br#"{"devId":"002004265ccf7fb1b659","dps":{"1":true},"reqType":"query","protocol":4}"#;
+        let payload = match Payload::try_from(json.to_vec()).unwrap() {
+            Payload::Struct(s) => s,
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        };
+        assert_eq!(payload.extra["reqType"], json!("query"));
+        assert_eq!(payload.extra["protocol"], json!(4));
+        let round_trip: serde_json::Value =
+            serde_json::from_slice(&serde_json::to_vec(&payload).unwrap()).unwrap();
+        assert_eq!(
+            round_trip,
+            serde_json::from_slice::<serde_json::Value>(json).unwrap()
+        );
+    }
+
+    #[test]
+    fn reply_without_dps() {
+        let payload =
+            Payload::try_from(br#"{"devId":"002004265ccf7fb1b659","t":1529442366}"#.to_vec())
+                .unwrap();
+        let reply = match payload {
+            Payload::Struct(s) => s,
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        };
+        assert_eq!(reply.dps, None);
+        assert_eq!(reply.dp_bool("1"), None);
+        assert!(reply.named_dps(&DpsSchema::default()).is_empty());
+        assert_eq!(reply.truncate().dps, None);
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"devId":"002004265ccf7fb1b659","t":1529442366}"#
+        );
+    }
+
+    #[test]
+    fn reply_with_dp_id() {
+        let reply: PayloadStruct =
+            serde_json::from_str(r#"{"devId":"002004265ccf7fb1b659","dpId":[18,19,20]}"#).unwrap();
+        assert_eq!(reply.dp_id, Some(DpId::Higher.get_ids()));
+        assert_eq!(reply.dps, None);
+    }
+
+    #[test]
+    fn query_payload() {
+        match Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659") {
+            Payload::Struct(s) => {
+                assert_eq!(s.dev_id, "002004265ccf7fb1b659");
+                assert_eq!(s.gw_id.as_deref(), Some("002004265ccf7fb1b659"));
+                assert!(s.t.is_some());
+                assert_eq!(s.dps, Some(HashMap::new()));
+            }
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        }
+    }
+
+    #[test]
+    fn typed_dps_accessors() {
+        let payload = match PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .dp("2", 255)
+            .dp("3", "white")
+            .build()
+        {
+            Payload::Struct(s) => s,
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        };
+        assert_eq!(payload.dp_bool("1"), Some(true));
+        assert_eq!(payload.dp_u64("2"), Some(255));
+        assert_eq!(payload.dp_str("3"), Some("white"));
+        assert_eq!(payload.dp::<u8>("2"), Some(255));
+        assert_eq!(payload.dp::<String>("3"), Some("white".to_string()));
+        // Wrong type or missing key
+        assert_eq!(payload.dp_bool("2"), None);
+        assert_eq!(payload.dp::<bool>("3"), None);
+        assert_eq!(payload.dp_u64("4"), None);
     }
 }
@@ -2,36 +2,45 @@
 //! The message parser is the low level API which takes care of encoding and decoding of Payloads.
 //! The normal user should not need to interact with this directly to communicate with Tuya
 //! devices, but rather create an instance of the TuyaDevice struct.
-use crate::cipher::TuyaCipher;
+use crate::cipher::{maybe_strip_header, TuyaCipher};
+use crate::crc::crc;
 use crate::error::ErrorKind;
-use crate::{Payload, Result};
+use crate::{log_hex, Payload, Result, Truncate};
 use hex::FromHex;
-use log::{debug, error};
+use log::{debug, error, warn};
 use nom::{
-    bytes::complete::tag,
-    combinator::{map, peek, recognize},
+    bytes::complete::{tag, take},
+    combinator::{consumed, map_opt, peek, recognize},
+    error::Error as NomError,
     multi::{length_data, many1},
-    number::complete::be_u32,
+    number::complete::{be_u16, be_u32},
     sequence::tuple,
     IResult,
 };
 
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
 use std::cmp::PartialEq;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::str::FromStr;
 
-const UDP_KEY: &str = "yGAdlopoPVldABfn";
+/// The well known key of the UDP broadcasts. The MD5 of it is the key a MessageParser uses when
+/// it is created without a key.
+pub const UDP_KEY: &str = "yGAdlopoPVldABfn";
 
 lazy_static! {
     static ref PREFIX_BYTES: [u8; 4] = <[u8; 4]>::from_hex("000055AA").unwrap();
     static ref SUFFIX_BYTES: [u8; 4] = <[u8; 4]>::from_hex("0000AA55").unwrap();
+    static ref PREFIX_6699_BYTES: [u8; 4] = <[u8; 4]>::from_hex("00006699").unwrap();
+    static ref SUFFIX_6699_BYTES: [u8; 4] = <[u8; 4]>::from_hex("00009966").unwrap();
 }
 
-/// Human readable definitions of command bytes.
-#[derive(Debug, FromPrimitive, ToPrimitive, Clone, PartialEq)]
+/// Human readable definitions of command bytes. The values follow the command table of
+/// tinytuya. There is no dedicated reboot command in the LAN protocol. Serialized as its name.
+#[derive(Debug, FromPrimitive, ToPrimitive, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum CommandType {
     Udp = 0,
     ApConfig = 1,
@@ -48,11 +57,16 @@ pub enum CommandType {
     TokenBind = 12,
     ControlNew = 13,
     EnableWifi = 14,
+    WifiInfo = 15,
     DpQueryNew = 16,
     SceneExecute = 17,
     DpRefresh = 18,
     UdpNew = 19,
     ApConfigNew = 20,
+    /// Broadcast of 3.4 devices
+    BroadcastLpv34 = 35,
+    ReqDevInfo = 37,
+    LanExtStream = 64,
     LanGwActive = 240,
     LanSubDevRequest = 241,
     LanDeleteSubDev = 242,
@@ -69,21 +83,68 @@ pub enum CommandType {
     Error = 255,
 }
 
+/// Protocol 3.4 and 3.5 reuses the command bytes of Bind, RenameGw and RenameDevice for the
+/// session key negotiation.
+#[allow(non_upper_case_globals)]
+impl CommandType {
+    pub const SessKeyNegStart: CommandType = CommandType::Bind;
+    pub const SessKeyNegResp: CommandType = CommandType::RenameGw;
+    pub const SessKeyNegFinish: CommandType = CommandType::RenameDevice;
+}
+
+/// The Tuya protocol version a device speaks. It is parsed from strings like "3.3" or "ver3.3".
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum TuyaVersion {
+#[allow(clippy::enum_variant_names)]
+pub enum TuyaVersion {
     ThreeOne,
+    ThreeTwo,
     ThreeThree,
+    ThreeFour,
+    ThreeFive,
 }
 
 impl TuyaVersion {
+    /// Whether the devices of this version need their local key. 3.1 devices send and accept
+    /// plain messages, the later versions encrypt everything.
+    pub(crate) fn requires_key(&self) -> bool {
+        *self != TuyaVersion::ThreeOne
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         match &self {
             TuyaVersion::ThreeOne => b"3.1",
+            TuyaVersion::ThreeTwo => b"3.2",
             TuyaVersion::ThreeThree => b"3.3",
+            TuyaVersion::ThreeFour => b"3.4",
+            TuyaVersion::ThreeFive => b"3.5",
+        }
+    }
+
+    /// The prefix and suffix that frame the messages of this version.
+    fn framing(&self) -> ([u8; 4], [u8; 4]) {
+        match self {
+            TuyaVersion::ThreeFive => (*PREFIX_6699_BYTES, *SUFFIX_6699_BYTES),
+            _ => (*PREFIX_BYTES, *SUFFIX_BYTES),
+        }
+    }
+
+    /// Number of bytes used by the CRC32, or the HMAC-SHA256 in 3.4, at the end of a frame.
+    /// Frames in 3.5 are authenticated by the GCM tag instead.
+    fn checksum_len(&self) -> usize {
+        match &self {
+            TuyaVersion::ThreeOne | TuyaVersion::ThreeTwo | TuyaVersion::ThreeThree => 4,
+            TuyaVersion::ThreeFour => 32,
+            TuyaVersion::ThreeFive => 0,
         }
     }
 }
 
+impl fmt::Display for TuyaVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
 impl FromStr for TuyaVersion {
     type Err = ErrorKind;
 
@@ -92,8 +153,14 @@ impl FromStr for TuyaVersion {
         if version.len() > 1 && version[0].ends_with('3') {
             if version[1] == "1" {
                 return Ok(TuyaVersion::ThreeOne);
+            } else if version[1] == "2" {
+                return Ok(TuyaVersion::ThreeTwo);
             } else if version[1] == "3" {
                 return Ok(TuyaVersion::ThreeThree);
+            } else if version[1] == "4" {
+                return Ok(TuyaVersion::ThreeFour);
+            } else if version[1] == "5" {
+                return Ok(TuyaVersion::ThreeFive);
             }
             return Err(ErrorKind::VersionError(
                 version[0].to_string(),
@@ -110,89 +177,333 @@ impl FromStr for TuyaVersion {
 /// Representation of a message sent to and received from a Tuya device. The Payload is
 /// serialized to and deserialized from JSON. The sequence number, if sent in a command, will
 /// be included in the response to be able to connect command and response. The return code is
-/// only included if the Message is a response from a device.
-#[derive(Debug, PartialEq)]
+/// only included if the Message is a response from a device. A parsed response keeps its return
+/// code, so encoding it again gives the same frame layout. A Message can be serialized, e.g. to
+/// store the messages of a session and replay them.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Message {
     pub payload: Payload,
     pub command: Option<CommandType>,
+    /// The command number of a received frame when it is not a known CommandType
+    pub raw_command: Option<u32>,
     pub seq_nr: Option<u32>,
     pub ret_code: Option<u8>,
 }
 
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let command = match (&self.command, self.raw_command) {
+            (Some(command), _) => format!("{:?}", command),
+            (None, Some(raw)) => format!("Unknown({})", raw),
+            (None, None) => format!("{:?}", CommandType::Error),
+        };
         write!(
             f,
-            "Payload: \"{}\", Command: {:?}, Seq Nr: {:?}, Return Code: {:?}",
-            self.payload,
-            self.command.clone().unwrap_or(CommandType::Error),
-            self.seq_nr,
-            self.ret_code,
+            "Payload: \"{}\", Command: {}, Seq Nr: {:?}, Return Code: {:?}",
+            self.payload, command, self.seq_nr, self.ret_code,
         )
     }
 }
 
+impl Truncate for Message {
+    fn truncate(&self) -> Message {
+        let payload = match &self.payload {
+            Payload::Struct(s) => Payload::Struct(s.truncate()),
+            Payload::String(s) => Payload::String(s.clone()),
+        };
+        Message {
+            payload,
+            command: self.command.clone(),
+            raw_command: self.raw_command,
+            seq_nr: self.seq_nr,
+            ret_code: self.ret_code,
+        }
+    }
+}
+
 impl Message {
     pub fn new(payload: Payload, command: CommandType, seq_nr: Option<u32>) -> Message {
         Message {
             payload,
             command: Some(command),
+            raw_command: None,
             seq_nr,
             ret_code: None,
         }
     }
+
+    /// The error reported by the device, if the return code of a reply is not 0. The payload of
+    /// such a reply is the error message, e.g. "data format error".
+    pub fn device_error(&self) -> Option<ErrorKind> {
+        match self.ret_code {
+            Some(code) if code != 0 => Some(ErrorKind::DeviceError {
+                code,
+                message: self.payload.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The dps of the payload, None if the payload is a string or has no dps.
+    pub fn dps(&self) -> Option<&HashMap<String, serde_json::Value>> {
+        match &self.payload {
+            Payload::Struct(s) => s.dps.as_ref(),
+            Payload::String(_) => None,
+        }
+    }
 }
 
 /// The message parser takes care of encoding and parsing messages before send and after
 /// receive. It uses a TuyaCipher to encrypt and decrypt messages sent with the Tuya
-/// protocol version 3.3.
+/// protocol version 3.3, 3.4 and 3.5.
+#[derive(Clone)]
 pub struct MessageParser {
     version: TuyaVersion,
     cipher: TuyaCipher,
+    /// Ciphers of the candidate keys after the primary one, see create_with_keys().
+    fallback_ciphers: Vec<TuyaCipher>,
+    lenient_crc: bool,
+    dp_query_with_header: bool,
+    prefix: [u8; 4],
+    suffix: [u8; 4],
+}
+
+/// A single frame received from a device, after the integrity check and decryption.
+struct Frame {
+    seq_nr: u32,
+    command: u32,
+    ret_code: Option<u8>,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn into_message(self) -> Message {
+        let command: Option<CommandType> = FromPrimitive::from_u32(self.command);
+        Message {
+            payload: to_payload(&self.payload),
+            raw_command: command.is_none().then_some(self.command),
+            command,
+            seq_nr: Some(self.seq_nr),
+            ret_code: self.ret_code,
+        }
+    }
 }
 
 /// MessageParser encodes and parses messages sent to and from Tuya devices. It may or may not
 /// encrypt the message, depending on message type and TuyaVersion. Likewise, the parsing may or may
 /// not need decrypting.
 impl MessageParser {
+    /// Without a key, the MD5 of UDP_KEY is used. It only decrypts the UDP broadcasts, so a
+    /// warning is logged for the versions where devices need their own local key.
     pub fn create(ver: &str, key: Option<&str>) -> Result<MessageParser> {
         let version = TuyaVersion::from_str(ver)?;
+        if key.is_none() && version.requires_key() {
+            warn!(
+                "No local key given for version {}, the UDP key only decrypts the UDP broadcasts",
+                version
+            );
+        }
         let key = verify_key(key)?;
-        let cipher = TuyaCipher::create(&key, version.clone());
-        Ok(MessageParser { version, cipher })
+        Ok(MessageParser::with_key(version, &key))
     }
 
-    pub fn encode(&self, mes: &Message, encrypt: bool) -> Result<Vec<u8>> {
-        let mut encoded: Vec<u8> = vec![];
-        encoded.extend_from_slice(&*PREFIX_BYTES);
-        match mes.seq_nr {
-            Some(nr) => encoded.extend(&nr.to_be_bytes()),
-            None => encoded.extend(&0_u32.to_be_bytes()),
+    fn with_key(version: TuyaVersion, key: &[u8]) -> MessageParser {
+        let cipher = TuyaCipher::create(key, version.clone());
+        let (prefix, suffix) = version.framing();
+        MessageParser {
+            version,
+            cipher,
+            fallback_ciphers: vec![],
+            lenient_crc: false,
+            dp_query_with_header: false,
+            prefix,
+            suffix,
+        }
+    }
+
+    /// Create a MessageParser with several candidate local keys, e.g. while migrating a device
+    /// that has been paired again and it is not known which key it uses. Messages are encrypted
+    /// with the first key. Received payloads are decrypted with the first key that gives json.
+    /// The 3.4 and 3.5 session key negotiation and the 3.5 payloads only use the first key.
+    pub fn create_with_keys(ver: &str, keys: &[&str]) -> Result<MessageParser> {
+        let mut mp = MessageParser::create(ver, keys.first().copied())?;
+        for key in keys.iter().skip(1) {
+            let key = verify_key(Some(key))?;
+            mp.fallback_ciphers
+                .push(TuyaCipher::create(&key, mp.version.clone()));
         }
+        Ok(mp)
+    }
+
+    /// Create a MessageParser for the UDP broadcasts, with another discovery key than UDP_KEY,
+    /// for firmware that uses its own. Like UDP_KEY, the MD5 of the discovery key is used.
+    pub fn create_with_discovery_key(ver: &str, discovery_key: &str) -> Result<MessageParser> {
+        let version = TuyaVersion::from_str(ver)?;
+        Ok(MessageParser::with_key(
+            version,
+            &md5::compute(discovery_key).0,
+        ))
+    }
+
+    /// The protocol version the messages are encoded and parsed with.
+    pub fn version(&self) -> TuyaVersion {
+        self.version.clone()
+    }
+
+    pub(crate) fn set_commandtype(&self) -> CommandType {
+        match self.version {
+            TuyaVersion::ThreeFour | TuyaVersion::ThreeFive => CommandType::ControlNew,
+            _ => CommandType::Control,
+        }
+    }
+
+    pub(crate) fn get_commandtype(&self) -> CommandType {
+        match self.version {
+            TuyaVersion::ThreeFour | TuyaVersion::ThreeFive => CommandType::DpQueryNew,
+            _ => CommandType::DpQuery,
+        }
+    }
+
+    /// A MessageParser with the same key, for another version.
+    pub(crate) fn with_version(&self, version: TuyaVersion) -> MessageParser {
+        let (prefix, suffix) = version.framing();
+        MessageParser {
+            cipher: self.cipher.with_version(version.clone()),
+            fallback_ciphers: self
+                .fallback_ciphers
+                .iter()
+                .map(|cipher| cipher.with_version(version.clone()))
+                .collect(),
+            version,
+            lenient_crc: self.lenient_crc,
+            dp_query_with_header: self.dp_query_with_header,
+            prefix,
+            suffix,
+        }
+    }
+
+    /// Some devices calculate the CRC incorrectly. When lenient, a CRC (or 3.4 HMAC) mismatch is
+    /// logged as a warning and the frame is parsed anyway. The GCM tag of 3.5 is always checked,
+    /// since the frame can not be decrypted without it.
+    pub fn set_lenient_crc(&mut self, lenient: bool) {
+        self.lenient_crc = lenient;
+    }
+
+    /// 3.2 and 3.3 DpQuery and DpRefresh messages are encrypted without the version and MD5
+    /// header, which is what most devices expect. Some firmware rejects the headerless query,
+    /// typically by replying "data format error" or not at all, while commands work. Enable this
+    /// for such devices to send the header with these messages too. It has no effect on the
+    /// other versions.
+    pub fn set_dp_query_with_header(&mut self, with_header: bool) {
+        self.dp_query_with_header = with_header;
+    }
+
+    /// Use other markers than the standard ones of the version to frame the messages, for
+    /// devices with custom framing. The layout of the frames is still given by the version.
+    pub fn set_framing(&mut self, prefix: [u8; 4], suffix: [u8; 4]) {
+        self.prefix = prefix;
+        self.suffix = suffix;
+    }
+
+    pub(crate) fn set_session_key(&mut self, session_key: Vec<u8>) {
+        self.cipher.set_session_key(session_key);
+    }
+
+    #[cfg(feature = "session-key")]
+    pub(crate) fn session_key(&self) -> Option<&[u8]> {
+        self.cipher.session_key()
+    }
+
+    /// Encode a message. Control and DpQuery requests with a payload must carry a
+    /// Payload::Struct, since the device needs its fields, e.g. devId. Replies, that have a return
+    /// code, may carry any payload.
+    pub fn encode(&self, mes: &Message, encrypt: bool) -> Result<Vec<u8>> {
         let command = mes.command.clone().ok_or(ErrorKind::CommandTypeMissing)?;
-        encoded.extend([0, 0, 0, command.to_u8().unwrap()].iter());
+        match (&command, &mes.payload, mes.ret_code) {
+            (CommandType::Control | CommandType::DpQuery, Payload::String(s), None)
+                if !s.is_empty() =>
+            {
+                return Err(ErrorKind::InvalidPayloadForCommand(command))
+            }
+            _ => (),
+        }
         let payload = self.create_payload_header(mes, encrypt)?;
-        let ret_len = match mes.ret_code {
+        let encoded = self.encode_frame(mes.seq_nr.unwrap_or(0), command, mes.ret_code, payload)?;
+        debug!(
+            "Encoded message ({}):\n{}",
+            mes.seq_nr.unwrap_or(0),
+            log_hex(&encoded)
+        );
+
+        Ok(encoded)
+    }
+
+    fn encode_frame(
+        &self,
+        seq_nr: u32,
+        command: CommandType,
+        ret_code: Option<u8>,
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        if self.version == TuyaVersion::ThreeFive {
+            return self.encode_frame_6699(seq_nr, command, ret_code, payload);
+        }
+        // Header, return code, checksum and suffix
+        let mut encoded: Vec<u8> =
+            Vec::with_capacity(16 + 4 + payload.len() + self.version.checksum_len() + 4);
+        encoded.extend_from_slice(&self.prefix);
+        encoded.extend(&seq_nr.to_be_bytes());
+        encoded.extend(&command_code(&command)?.to_be_bytes());
+        let ret_len = match ret_code {
             Some(_) => 4_u32,
             None => 0_u32,
         };
         encoded.extend(
-            (payload.len() as u32 + 8_u32 + ret_len)
+            (payload.len() as u32 + self.version.checksum_len() as u32 + 4_u32 + ret_len)
                 .to_be_bytes()
                 .iter(),
         );
-        if let Some(ret_code) = mes.ret_code {
-            encoded.extend(&ret_code.to_be_bytes());
+        // The return code takes 4 bytes, as counted in the length above
+        if let Some(ret_code) = ret_code {
+            encoded.extend(&(ret_code as u32).to_be_bytes());
         }
         encoded.extend(payload);
-        encoded.extend(crc32fast::hash(&encoded).to_be_bytes().iter());
-        encoded.extend_from_slice(&*SUFFIX_BYTES);
-        debug!(
-            "Encoded message ({}):\n{}",
-            mes.seq_nr.unwrap_or(0),
-            hex::encode(&encoded)
-        );
+        match self.version {
+            TuyaVersion::ThreeFour => {
+                let hmac = self.cipher.hmac(&encoded)?;
+                encoded.extend(hmac);
+            }
+            _ => encoded.extend(crc(&encoded).to_be_bytes().iter()),
+        }
+        encoded.extend_from_slice(&self.suffix);
+        Ok(encoded)
+    }
 
+    /// Protocol 3.5 frames start with 00006699 followed by two reserved bytes. The return code
+    /// is encrypted together with the payload, and the header after the prefix is used as
+    /// additional authenticated data.
+    fn encode_frame_6699(
+        &self,
+        seq_nr: u32,
+        command: CommandType,
+        ret_code: Option<u8>,
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut data: Vec<u8> = vec![];
+        if let Some(ret_code) = ret_code {
+            data.extend(&(ret_code as u32).to_be_bytes());
+        }
+        data.extend(payload);
+        let mut encoded: Vec<u8> = vec![];
+        encoded.extend_from_slice(&self.prefix);
+        encoded.extend(&0_u16.to_be_bytes());
+        encoded.extend(&seq_nr.to_be_bytes());
+        encoded.extend(&command_code(&command)?.to_be_bytes());
+        // 12 bytes nonce and 16 bytes tag
+        encoded.extend(&(data.len() as u32 + 28_u32).to_be_bytes());
+        let encrypted = self.cipher.encrypt_gcm(&data, &encoded[4..])?;
+        encoded.extend(encrypted);
+        encoded.extend_from_slice(&self.suffix);
         Ok(encoded)
     }
 
@@ -200,125 +511,403 @@ impl MessageParser {
         match self.version {
             TuyaVersion::ThreeOne => {
                 if encrypt {
-                    self.create_payload_with_header(mes.payload.clone().try_into()?)
+                    self.create_payload_with_header((&mes.payload).try_into()?)
                 } else {
-                    mes.payload.clone().try_into()
+                    (&mes.payload).try_into()
                 }
             }
-            TuyaVersion::ThreeThree => match mes.command {
-                Some(CommandType::DpQuery) | Some(CommandType::DpRefresh) => {
-                    let payload: Vec<u8> = mes.payload.clone().try_into()?;
+            // 3.2 is encrypted the same way as 3.3, without base64.
+            TuyaVersion::ThreeTwo | TuyaVersion::ThreeThree => match mes.command {
+                Some(CommandType::DpQuery) | Some(CommandType::DpRefresh)
+                    if !self.dp_query_with_header =>
+                {
+                    let payload: Vec<u8> = (&mes.payload).try_into()?;
                     self.cipher.encrypt(&payload)
                 }
-                _ => self.create_payload_with_header(mes.payload.clone().try_into()?),
+                _ => self.create_payload_with_header((&mes.payload).try_into()?),
             },
+            // 3.4 and 3.5 is always encrypted and has no MD5 in the header. The header is put
+            // inside the encrypted data.
+            TuyaVersion::ThreeFour | TuyaVersion::ThreeFive => {
+                let payload: Vec<u8> = (&mes.payload).try_into()?;
+                match mes.command {
+                    Some(CommandType::DpQuery)
+                    | Some(CommandType::DpQueryNew)
+                    | Some(CommandType::DpRefresh)
+                    | Some(CommandType::HeartBeat) => self.encrypt_payload(&payload),
+                    _ => {
+                        let mut payload_with_header = Vec::new();
+                        payload_with_header.extend(self.version.as_bytes());
+                        payload_with_header.extend(vec![0; 12]);
+                        payload_with_header.extend(payload);
+                        self.encrypt_payload(&payload_with_header)
+                    }
+                }
+            }
         }
     }
 
     fn create_payload_with_header(&self, payload: Vec<u8>) -> Result<Vec<u8>> {
         let mut payload_with_header = Vec::new();
         payload_with_header.extend(self.version.as_bytes());
+        let encrypted = self.cipher.encrypt(&payload)?;
         match self.version {
-            TuyaVersion::ThreeOne => payload_with_header.extend(vec![0; 12]),
-            TuyaVersion::ThreeThree => payload_with_header.extend(self.cipher.md5(&payload)),
+            // 3.1 signs the encrypted payload, with part of the hex MD5
+            TuyaVersion::ThreeOne => payload_with_header.extend(self.cipher.md5_hex(&encrypted)),
+            _ => payload_with_header.extend(self.cipher.md5(&payload)),
         }
-        payload_with_header.extend(self.cipher.encrypt(&payload)?);
+        payload_with_header.extend(encrypted);
         Ok(payload_with_header)
     }
 
+    /// In 3.5 the whole frame is encrypted when it is encoded, so the payload is left as is.
+    fn encrypt_payload(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match self.version {
+            TuyaVersion::ThreeFive => Ok(payload.to_vec()),
+            _ => self.cipher.encrypt(payload),
+        }
+    }
+
+    /// Create the first message of the 3.4 and 3.5 session key negotiation, which carries the
+    /// local nonce encrypted with the local key.
+    pub(crate) fn encode_session_key_start(
+        &self,
+        local_nonce: &[u8],
+        seq_nr: u32,
+    ) -> Result<Vec<u8>> {
+        let payload = self.encrypt_payload(local_nonce)?;
+        self.encode_frame(seq_nr, CommandType::SessKeyNegStart, None, payload)
+    }
+
+    /// Verify the negotiation response from the device and create the message that finishes
+    /// the negotiation. Returns the finish message together with the negotiated session key.
+    pub(crate) fn encode_session_key_finish(
+        &self,
+        local_nonce: &[u8],
+        buf: &[u8],
+        seq_nr: u32,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let response = complete(self.parse_frames(buf))?
+            .into_iter()
+            .find(|frame| frame.command == CommandType::SessKeyNegResp.to_u32().unwrap())
+            .ok_or(ErrorKind::SessionKeyNegotiationError)?;
+        // The response is the remote nonce followed by the HMAC of the local nonce
+        if response.payload.len() < 48 {
+            return Err(ErrorKind::SessionKeyNegotiationError);
+        }
+        let (remote_nonce, local_hmac) = response.payload.split_at(16);
+        if local_hmac[..32] != self.cipher.hmac(local_nonce)?[..] {
+            error!("The device responded with an invalid HMAC during session key negotiation");
+            return Err(ErrorKind::SessionKeyNegotiationError);
+        }
+        let payload = self.encrypt_payload(&self.cipher.hmac(remote_nonce)?)?;
+        let finish = self.encode_frame(seq_nr, CommandType::SessKeyNegFinish, None, payload)?;
+        let session_key = self
+            .cipher
+            .derive_session_key(local_nonce, &remote_nonce[..16])?;
+        Ok((finish, session_key))
+    }
+
+    /// Parse all frames in buf. Fails with ErrorKind::ParsingIncomplete if the last frame is cut
+    /// short, so the caller knows to read more, and with ErrorKind::ParseError if a frame is
+    /// corrupt.
     pub fn parse(&self, buf: &[u8]) -> Result<Vec<Message>> {
-        let (buf, messages) = self.parse_messages(buf).map_err(|err| match err {
-            nom::Err::Error(e) => ErrorKind::ParseError(e.code),
-            nom::Err::Incomplete(_) => ErrorKind::ParsingIncomplete,
-            nom::Err::Failure(e) if e.code == nom::error::ErrorKind::ManyMN => ErrorKind::CRCError,
-            nom::Err::Failure(e) => ErrorKind::ParseError(e.code),
-        })?;
-        if !buf.is_empty() {
-            return Err(ErrorKind::BufferNotCompletelyParsedError);
-        }
-        Ok(messages)
-    }
-
-    fn parse_messages<'a>(&self, orig_buf: &'a [u8]) -> IResult<&'a [u8], Vec<Message>> {
-        // TODO: can this be statically initialized??
-        let be_u32_minus4 = map(be_u32, |n: u32| n - 4);
-        let (buf, vec) = many1(tuple((
-            tag(*PREFIX_BYTES),
+        // A frame that is cut short, e.g. with its suffix still to be read, needs more bytes
+        // rather than being corrupt
+        let mut rest = buf;
+        while !rest.is_empty() {
+            match self.frame_len(rest) {
+                Ok(Some(len)) if len <= rest.len() => rest = &rest[len..],
+                Ok(_) => return Err(ErrorKind::ParsingIncomplete),
+                // Left to the grammar, which reports where the frame is broken
+                Err(_) => break,
+            }
+        }
+        complete(self.parse_messages(buf))
+    }
+
+    /// Parse each frame in `buf` on its own, so that a corrupt frame does not discard the others.
+    /// Returns the messages of the valid frames together with the errors of the invalid ones.
+    /// Parsing stops at a frame whose header is broken, since the start of the next frame can not
+    /// be found without it.
+    pub fn parse_best_effort(&self, buf: &[u8]) -> (Vec<Message>, Vec<ErrorKind>) {
+        let mut messages = vec![];
+        let mut errors = vec![];
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let len = match self.frame_len(rest) {
+                Ok(Some(len)) if len <= rest.len() => len,
+                Ok(_) => {
+                    errors.push(ErrorKind::ParsingIncomplete);
+                    break;
+                }
+                Err(e) => {
+                    errors.push(e);
+                    break;
+                }
+            };
+            match self.parse(&rest[..len]) {
+                Ok(parsed) => messages.extend(parsed),
+                Err(e) => errors.push(e),
+            }
+            rest = &rest[len..];
+        }
+        (messages, errors)
+    }
+
+    /// Like parse(), but skips any bytes before the first frame prefix, e.g. when a UDP read or a
+    /// serial bridge starts mid-frame or with noise. Returns the messages together with the
+    /// number of bytes skipped. Fails with ErrorKind::ParseError if `buf` contains no prefix.
+    pub fn parse_resync(&self, buf: &[u8]) -> Result<(Vec<Message>, usize)> {
+        let skipped = buf
+            .windows(self.prefix.len())
+            .position(|window| window == self.prefix)
+            .ok_or(ErrorKind::ParseError(nom::error::ErrorKind::Tag))?;
+        if skipped > 0 {
+            debug!("Skipping {} bytes before the frame prefix", skipped);
+        }
+        Ok((self.parse(&buf[skipped..])?, skipped))
+    }
+
+    /// Parse the complete frames at the start of `buf`, for buffers that are filled as data
+    /// arrives. Returns the messages together with the number of bytes they used. A partial frame
+    /// at the end is left for the next call, once more data has been appended to the buffer.
+    pub fn parse_stream(&self, buf: &[u8]) -> Result<(Vec<Message>, usize)> {
+        let mut used = 0;
+        while let Some(len) = self.frame_len(&buf[used..])? {
+            if buf.len() - used < len {
+                break;
+            }
+            used += len;
+        }
+        if used == 0 {
+            return Ok((vec![], 0));
+        }
+        let messages = complete(self.parse_messages(&buf[..used]))?;
+        Ok((messages, used))
+    }
+
+    /// The length of the frame starting at `buf`, read from its header. None if the header is not
+    /// complete yet.
+    fn frame_len(&self, buf: &[u8]) -> Result<Option<usize>> {
+        let header_len = match self.version {
+            TuyaVersion::ThreeFive => 18,
+            _ => 16,
+        };
+        let available = buf.len().min(self.prefix.len());
+        if buf[..available] != self.prefix[..available] {
+            return Err(ErrorKind::ParseError(nom::error::ErrorKind::Tag));
+        }
+        if buf.len() < header_len {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([
+            buf[header_len - 4],
+            buf[header_len - 3],
+            buf[header_len - 2],
+            buf[header_len - 1],
+        ]) as usize;
+        Ok(Some(match self.version {
+            // The 3.5 length does not include the suffix
+            TuyaVersion::ThreeFive => header_len + len + self.suffix.len(),
+            _ => header_len + len,
+        }))
+    }
+
+    fn parse_messages<'a>(&self, orig_buf: &'a [u8]) -> Result<(&'a [u8], Vec<Message>)> {
+        let (buf, frames) = self.parse_frames(orig_buf)?;
+        Ok((buf, frames.into_iter().map(Frame::into_message).collect()))
+    }
+
+    /// Splits the buffer into frames and verifies their checksums. A checksum mismatch is
+    /// reported as `ErrorKind::CRCError`, everything else the grammar rejects as `ParseError`.
+    fn parse_frames<'a>(&self, orig_buf: &'a [u8]) -> Result<(&'a [u8], Vec<Frame>)> {
+        if self.version == TuyaVersion::ThreeFive {
+            return self.parse_frames_6699(orig_buf);
+        }
+        let (buf, vec) = many1(consumed(tuple::<_, _, NomError<_>, _>((
+            tag(self.prefix),
             be_u32,
             be_u32,
             length_data(be_u32_minus4),
-            tag(*SUFFIX_BYTES),
-        )))(orig_buf)?;
-        let mut messages = vec![];
-        for (_, seq_nr, command, recv_data, _) in vec {
+            tag(self.suffix),
+        ))))(orig_buf)?;
+        let mut frames = vec![];
+        for (frame, (_, seq_nr, command, recv_data, _)) in vec {
             // check if the recv_data contains a return code
-            let (recv_data, maybe_retcode) = peek(be_u32)(recv_data)?;
+            let (recv_data, maybe_retcode) = peek(be_u32::<_, NomError<_>>)(recv_data)?;
             let (recv_data, ret_code, ret_len) = if maybe_retcode & 0xFFFF_FF00 == 0 {
                 // Has a return code
-                let (recv_data, ret_code) = recognize(be_u32)(recv_data)?;
+                let (recv_data, ret_code) = recognize(be_u32::<_, NomError<_>>)(recv_data)?;
                 (recv_data, Some(ret_code[3]), 4_usize)
             } else {
                 // Has no return code
                 (recv_data, None, 0_usize)
             };
-            let (payload, rc) = recv_data.split_at(recv_data.len() - 4);
-            let recv_crc = u32::from_be_bytes([rc[0], rc[1], rc[2], rc[3]]);
-            let crc = crc32fast::hash(&orig_buf[0..recv_data.len() + 12 + ret_len]);
-            if crc != recv_crc {
-                error!("Found CRC: {:#x}, Expected CRC: {:#x}", recv_crc, crc);
-                // I hijack the ErrorKind::ManyMN here to propagate a CRC error
-                // TODO: should probably create and use a special CRC error here
-                return Err(nom::Err::Failure(nom::error::Error::new(
-                    rc,
-                    nom::error::ErrorKind::ManyMN,
-                )));
+            let checksum_start = recv_data
+                .len()
+                .checked_sub(self.version.checksum_len())
+                .ok_or(ErrorKind::ParseError(nom::error::ErrorKind::LengthValue))?;
+            let (payload, rc) = recv_data.split_at(checksum_start);
+            // The checksum covers the frame up to the checksum itself
+            let checked = &frame[0..payload.len() + 16 + ret_len];
+            let valid = match self.version {
+                TuyaVersion::ThreeFour => self.cipher.hmac(checked).is_ok_and(|hmac| hmac == rc),
+                _ => {
+                    let recv_crc = u32::from_be_bytes([rc[0], rc[1], rc[2], rc[3]]);
+                    let crc = crc(checked);
+                    if crc != recv_crc && !self.lenient_crc {
+                        error!("Found CRC: {:#x}, Expected CRC: {:#x}", recv_crc, crc);
+                    }
+                    crc == recv_crc
+                }
+            };
+            if !valid {
+                if !self.lenient_crc {
+                    return Err(ErrorKind::CRCError);
+                }
+                warn!("Ignoring the checksum mismatch of frame {}", seq_nr);
             }
 
-            let payload = self.try_decrypt(payload);
-            let message = Message {
-                payload,
-                command: FromPrimitive::from_u32(command).or(None),
-                seq_nr: Some(seq_nr),
+            frames.push(Frame {
+                seq_nr,
+                command,
                 ret_code,
-            };
-            messages.push(message);
+                payload: self.try_decrypt(payload),
+            });
         }
-        Ok((buf, messages))
+        Ok((buf, frames))
     }
 
-    fn try_decrypt(&self, payload: &[u8]) -> Payload {
-        match self.cipher.decrypt(payload) {
-            Ok(decrypted) => {
-                if let Ok(p) = serde_json::from_slice(&decrypted) {
-                    Payload::Struct(p)
-                } else {
-                    Payload::String(
-                        std::str::from_utf8(&decrypted)
-                            .unwrap_or("Payload invalid")
-                            .to_string(),
-                    )
-                }
-            }
-            Err(_) => {
-                if let Ok(p) = serde_json::from_slice(payload) {
-                    Payload::Struct(p)
-                } else {
-                    Payload::String(
-                        std::str::from_utf8(payload)
-                            .unwrap_or("Payload invalid")
-                            .to_string(),
-                    )
+    fn parse_frames_6699<'a>(&self, orig_buf: &'a [u8]) -> Result<(&'a [u8], Vec<Frame>)> {
+        let (buf, vec) = many1(tuple::<_, _, NomError<_>, _>((
+            tag(self.prefix),
+            peek(take(14_usize)),
+            be_u16,
+            be_u32,
+            be_u32,
+            length_data(be_u32),
+            tag(self.suffix),
+        )))(orig_buf)?;
+        let mut frames = vec![];
+        for (_, aad, _, seq_nr, command, recv_data, _) in vec {
+            let data = match self.cipher.decrypt_gcm(recv_data, aad) {
+                Ok(data) => data,
+                Err(_) => {
+                    error!("The GCM tag of the received frame does not match");
+                    // The GCM tag is the checksum of 3.5, report it as a CRC error
+                    return Err(ErrorKind::CRCError);
                 }
+            };
+            let (data, ret_code) = if data.len() >= 4 && data[..3] == [0, 0, 0] {
+                (&data[4..], Some(data[3]))
+            } else {
+                (&data[..], None)
+            };
+            frames.push(Frame {
+                seq_nr,
+                command,
+                ret_code,
+                payload: maybe_strip_header(&self.version, data),
+            });
+        }
+        Ok((buf, frames))
+    }
+
+    fn try_decrypt(&self, payload: &[u8]) -> Vec<u8> {
+        // Nothing to decrypt. Unencrypted 3.1 payloads are plain json, while the encrypted ones
+        // are base64 and can not start with a brace.
+        if payload.is_empty()
+            || (self.version == TuyaVersion::ThreeOne && payload.starts_with(b"{"))
+        {
+            return payload.to_vec();
+        }
+        let decrypted = self.cipher.decrypt(payload);
+        // With candidate keys, the first one that gives json is the right one
+        if !self.fallback_ciphers.is_empty() && !decrypted.as_deref().is_ok_and(is_json) {
+            let fallback = self
+                .fallback_ciphers
+                .iter()
+                .enumerate()
+                .find_map(|(i, cipher)| {
+                    let data = cipher.decrypt(payload).ok().filter(|data| is_json(data))?;
+                    debug!("Decrypted with candidate key {}", i + 2);
+                    Some(data)
+                });
+            if let Some(data) = fallback {
+                return data;
             }
         }
+        decrypted.unwrap_or_else(|e| {
+            debug!("Using the payload as is: {}", e);
+            payload.to_vec()
+        })
+    }
+}
+
+fn is_json(data: &[u8]) -> bool {
+    serde_json::from_slice::<serde::de::IgnoredAny>(data).is_ok()
+}
+
+/// The length field of a frame, which also counts the 4 bytes of the suffix. A length that can
+/// not even hold the suffix is rejected.
+fn be_u32_minus4(buf: &[u8]) -> IResult<&[u8], u32, NomError<&[u8]>> {
+    map_opt(be_u32, |n: u32| n.checked_sub(4))(buf)
+}
+
+fn complete<T>(result: Result<(&[u8], Vec<T>)>) -> Result<Vec<T>> {
+    let (buf, parsed) = result?;
+    if !buf.is_empty() {
+        return Err(ErrorKind::BufferNotCompletelyParsedError);
+    }
+    Ok(parsed)
+}
+
+fn to_payload(data: &[u8]) -> Payload {
+    if let Ok(p) = serde_json::from_slice(data) {
+        Payload::Struct(p)
+    } else {
+        Payload::String(
+            std::str::from_utf8(data)
+                .unwrap_or("Payload invalid")
+                .to_string(),
+        )
     }
 }
 
+/// The code of command in the frame header. Every CommandType has a code today, a variant
+/// without one is reported as an error rather than a panic.
+fn command_code(command: &CommandType) -> Result<u32> {
+    command
+        .to_u32()
+        .ok_or(ErrorKind::CanNotEncodeMessageWithoutCommand)
+}
+
+/// The MD5 of `data=<payload>||lpv=<version>||<key>`, where version is e.g. `3.3` and key is
+/// the 16 byte local key. The headers of encrypted payloads are taken from it, see md5_header().
+pub fn md5_digest(key: &[u8], version: &TuyaVersion, payload: &[u8]) -> [u8; 16] {
+    let hash_line = [b"data=", payload, b"||lpv=", version.as_bytes(), b"||", key].concat();
+    md5::compute(hash_line).0
+}
+
+/// The 12 bytes that follow the version in the header of encrypted 3.2 and 3.3 payloads. It is
+/// bytes 4 to 16 of md5_digest() of the json before encryption.
+pub fn md5_header(key: &[u8], version: &TuyaVersion, payload: &[u8]) -> [u8; 12] {
+    md5_digest(key, version, payload)[4..16].try_into().unwrap()
+}
+
+/// The local key is 16 ascii characters. A key of 32 hex digits is also accepted, since that is
+/// a common way to copy the key, and is decoded to the 16 bytes it represents.
 fn verify_key(key: Option<&str>) -> Result<Vec<u8>> {
     match key {
         Some(key) => {
+            let looks_like_hex = key.len() > 16 && key.chars().all(|c| c.is_ascii_hexdigit());
             if key.len() == 16 {
                 Ok(key.as_bytes().to_vec())
+            } else if looks_like_hex && key.len() == 32 {
+                Ok(hex::decode(key)?)
+            } else if looks_like_hex {
+                Err(ErrorKind::HexKeyLength(key.len()))
             } else {
                 Err(ErrorKind::KeyLength(key.len()))
             }
@@ -330,12 +919,35 @@ fn verify_key(key: Option<&str>) -> Result<Vec<u8>> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "crypto")))]
+mod no_crypto_tests {
+    use super::*;
+    use crate::PayloadStruct;
+
+    #[test]
+    fn plain_version_three_one_without_crypto() {
+        let parser = MessageParser::create("3.1", None).unwrap();
+        let mes = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let encoded = parser.encode(&mes, false).unwrap();
+        assert_eq!(parser.parse(&encoded).unwrap()[0].payload, mes.payload);
+        assert!(matches!(
+            parser.encode(&mes, true),
+            Err(ErrorKind::CryptoDisabled)
+        ));
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
 mod tests {
     use super::*;
     use crate::PayloadStruct;
     use serde_json::json;
-    use std::collections::HashMap;
     #[test]
     fn test_key_length_is_16() {
         let key = Some("0123456789ABCDEF");
@@ -348,15 +960,204 @@ mod tests {
         assert!(verify_key(bad_key).is_err());
     }
 
+    #[test]
+    fn test_create_with_discovery_key() {
+        let mes = Message::new(
+            Payload::String(r#"{"ip":"192.168.0.123"}"#.to_string()),
+            CommandType::UdpNew,
+            None,
+        );
+        let default = MessageParser::create("3.3", None).unwrap();
+        let same = MessageParser::create_with_discovery_key("3.3", UDP_KEY).unwrap();
+        let encoded = default.encode(&mes, true).unwrap();
+        assert_eq!(same.parse(&encoded).unwrap()[0].payload, mes.payload);
+        let other = MessageParser::create_with_discovery_key("3.3", "0123456789abcdef").unwrap();
+        let encoded = other.encode(&mes, true).unwrap();
+        assert_eq!(other.parse(&encoded).unwrap()[0].payload, mes.payload);
+        assert_ne!(default.parse(&encoded).unwrap()[0].payload, mes.payload);
+    }
+
+    #[test]
+    fn test_create_with_keys() {
+        let mes = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let old = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let new = MessageParser::create("3.3", Some("0123456789abcdef")).unwrap();
+        let candidates =
+            MessageParser::create_with_keys("3.3", &["0123456789abcdef", "bbe88b3f4106d354"])
+                .unwrap();
+        for mp in [&old, &new] {
+            let encoded = mp.encode(&mes, true).unwrap();
+            assert_eq!(candidates.parse(&encoded).unwrap()[0].payload, mes.payload);
+        }
+        // The first key is used to encrypt
+        let encoded = candidates.encode(&mes, true).unwrap();
+        assert_eq!(new.parse(&encoded).unwrap()[0].payload, mes.payload);
+        assert!(MessageParser::create_with_keys("3.3", &["0123456789abcdef", "short"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_frame_without_suffix_is_incomplete() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let mes = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let frame = mp.encode(&mes, true).unwrap();
+        for cut in [1, 4, 10] {
+            assert!(matches!(
+                mp.parse(&frame[..frame.len() - cut]),
+                Err(ErrorKind::ParsingIncomplete)
+            ));
+        }
+        let two_frames = [&frame[..], &frame[..frame.len() - 2]].concat();
+        assert!(matches!(
+            mp.parse(&two_frames),
+            Err(ErrorKind::ParsingIncomplete)
+        ));
+        // A wrong suffix is corrupt, not incomplete
+        let mut corrupt = frame.clone();
+        *corrupt.last_mut().unwrap() = 0;
+        assert!(matches!(mp.parse(&corrupt), Err(ErrorKind::ParseError(_))));
+    }
+
+    #[test]
+    fn test_dp_query_with_header() {
+        let mes = Message::new(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let mut mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let headerless = mp.encode(&mes, true).unwrap();
+        mp.set_dp_query_with_header(true);
+        let with_header = mp.encode(&mes, true).unwrap();
+        // The payload starts after the 16 byte frame header
+        assert_ne!(&headerless[16..19], b"3.3");
+        assert_eq!(&with_header[16..19], b"3.3");
+        assert_eq!(with_header.len(), headerless.len() + 15);
+        assert_eq!(mp.parse(&with_header).unwrap()[0].payload, mes.payload);
+    }
+
+    #[test]
+    fn test_md5_header() {
+        let header = md5_header(
+            b"bbe88b3f4106d354",
+            &TuyaVersion::ThreeThree,
+            br#"{"devId":"002004265ccf7fb1b659","dps":{"1":true}}"#,
+        );
+        assert_eq!(hex::encode(header), "7c12b996e111cbaf9a95d752");
+    }
+
+    #[test]
+    fn test_hex_key_is_decoded() {
+        let key = Some("30313233343536373839414243444546");
+        assert_eq!(verify_key(key).unwrap(), b"0123456789ABCDEF");
+    }
+
+    #[test]
+    fn test_hex_key_with_wrong_length_gives_hex_error() {
+        let bad_key = Some("303132333435363738394142434445");
+        assert!(matches!(
+            verify_key(bad_key),
+            Err(ErrorKind::HexKeyLength(30))
+        ));
+        let bad_key = Some("this key is not hex at all");
+        assert!(matches!(verify_key(bad_key), Err(ErrorKind::KeyLength(26))));
+    }
+
+    #[test]
+    fn test_command_type_round_trip() {
+        let commands = [
+            CommandType::Udp,
+            CommandType::ApConfig,
+            CommandType::Active,
+            CommandType::Bind,
+            CommandType::RenameGw,
+            CommandType::RenameDevice,
+            CommandType::Unbind,
+            CommandType::Control,
+            CommandType::Status,
+            CommandType::HeartBeat,
+            CommandType::DpQuery,
+            CommandType::QueryWifi,
+            CommandType::TokenBind,
+            CommandType::ControlNew,
+            CommandType::EnableWifi,
+            CommandType::WifiInfo,
+            CommandType::DpQueryNew,
+            CommandType::SceneExecute,
+            CommandType::DpRefresh,
+            CommandType::UdpNew,
+            CommandType::ApConfigNew,
+            CommandType::BroadcastLpv34,
+            CommandType::ReqDevInfo,
+            CommandType::LanExtStream,
+            CommandType::LanGwActive,
+            CommandType::LanSubDevRequest,
+            CommandType::LanDeleteSubDev,
+            CommandType::LanReportSubDev,
+            CommandType::LanScene,
+            CommandType::LanPublishCloudConfig,
+            CommandType::LanPublishAppConfig,
+            CommandType::LanExportAppConfig,
+            CommandType::LanPublishScenePanel,
+            CommandType::LanRemoveGw,
+            CommandType::LanCheckGwUpdate,
+            CommandType::LanGwUpdate,
+            CommandType::LanSetGwChannel,
+            CommandType::Error,
+        ];
+        for command in commands {
+            let byte = command.to_u8().unwrap();
+            assert_eq!(CommandType::from_u32(byte as u32), Some(command));
+        }
+        assert_eq!(CommandType::from_u32(15), Some(CommandType::WifiInfo));
+        assert_eq!(CommandType::from_u32(35), Some(CommandType::BroadcastLpv34));
+        assert_eq!(CommandType::from_u32(64), Some(CommandType::LanExtStream));
+    }
+
     #[test]
     fn test_parse_mqttversion() {
         let version = TuyaVersion::from_str("3.1").unwrap();
         assert_eq!(version, TuyaVersion::ThreeOne);
 
+        let version1 = TuyaVersion::from_str("3.2").unwrap();
+        assert_eq!(version1, TuyaVersion::ThreeTwo);
+
         let version2 = TuyaVersion::from_str("ver3.3").unwrap();
         assert_eq!(version2, TuyaVersion::ThreeThree);
 
-        assert!(TuyaVersion::from_str("3.4").is_err());
+        let version3 = TuyaVersion::from_str("3.4").unwrap();
+        assert_eq!(version3, TuyaVersion::ThreeFour);
+
+        let version4 = TuyaVersion::from_str("3.5").unwrap();
+        assert_eq!(version4, TuyaVersion::ThreeFive);
+
+        assert!(TuyaVersion::from_str("3.6").is_err());
+    }
+
+    #[test]
+    fn test_message_parser_version() {
+        let mp = MessageParser::create("ver3.4", Some("bbe88b3f4106d354")).unwrap();
+        assert_eq!(mp.version(), TuyaVersion::ThreeFour);
+    }
+
+    #[test]
+    fn test_display_mqttversion() {
+        for ver in ["3.1", "3.2", "3.3", "3.4", "3.5"] {
+            let version = TuyaVersion::from_str(ver).unwrap();
+            assert_eq!(version.to_string(), ver);
+            assert_eq!(version.as_bytes(), ver.as_bytes());
+        }
     }
 
     #[test]
@@ -365,6 +1166,7 @@ mod tests {
             hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
         let expected = Message {
             command: Some(CommandType::HeartBeat),
+            raw_command: None,
             payload: Payload::String("".to_string()),
             seq_nr: Some(0),
             ret_code: Some(0),
@@ -375,6 +1177,274 @@ mod tests {
         assert_eq!(buf, &[] as &[u8]);
     }
 
+    #[test]
+    fn test_parse_error_is_not_crc_error() {
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let packet =
+            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+
+        // A broken prefix is a parse error
+        let mut bad_prefix = packet.clone();
+        bad_prefix[0] = 0xff;
+        assert!(matches!(
+            mp.parse(&bad_prefix),
+            Err(ErrorKind::ParseError(nom::error::ErrorKind::Tag))
+        ));
+
+        // A broken checksum is a CRC error
+        let mut bad_crc = packet;
+        bad_crc[23] ^= 0xff;
+        assert!(matches!(mp.parse(&bad_crc), Err(ErrorKind::CRCError)));
+    }
+
+    #[test]
+    fn test_truncate_message() {
+        let mes = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::Control,
+            Some(1),
+        );
+        let truncated = mes.truncate();
+        match truncated.payload {
+            Payload::Struct(s) => assert_eq!(s.dev_id, "...b1b659"),
+            Payload::String(_) => panic!("Expected a Payload::Struct"),
+        }
+        assert_eq!(truncated.command, mes.command);
+        assert_eq!(truncated.seq_nr, mes.seq_nr);
+    }
+
+    #[test]
+    fn test_parse_unknown_command_keeps_raw_value() {
+        let frame = Frame {
+            seq_nr: 1,
+            command: 99,
+            ret_code: None,
+            payload: vec![],
+        };
+        let mes = frame.into_message();
+        assert_eq!(mes.command, None);
+        assert_eq!(mes.raw_command, Some(99));
+        assert!(mes.to_string().contains("Command: Unknown(99)"));
+
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let packet =
+            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+        assert_eq!(mp.parse(&packet).unwrap()[0].raw_command, None);
+    }
+
+    #[test]
+    fn test_lenient_crc_parses_frame_with_bad_crc() {
+        let packet =
+            hex::decode("000055aa00000000000000090000000c00000000deadbeef0000aa55").unwrap();
+        let mut mp = MessageParser::create("3.3", None).unwrap();
+        assert!(matches!(mp.parse(&packet), Err(ErrorKind::CRCError)));
+        mp.set_lenient_crc(true);
+        let messages = mp.parse(&packet).unwrap();
+        assert_eq!(messages[0].command, Some(CommandType::HeartBeat));
+    }
+
+    #[test]
+    fn test_encode_string_payload_for_control_gives_error() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        for command in [CommandType::Control, CommandType::DpQuery] {
+            let mes = Message::new(Payload::from(r#"{"1":true}"#), command.clone(), Some(1));
+            match mp.encode(&mes, true) {
+                Err(ErrorKind::InvalidPayloadForCommand(c)) => assert_eq!(c, command),
+                _ => panic!("Expected InvalidPayloadForCommand"),
+            }
+        }
+        let heartbeat = Message::new(Payload::from("{}"), CommandType::HeartBeat, Some(1));
+        assert!(mp.encode(&heartbeat, true).is_ok());
+    }
+
+    #[test]
+    fn test_message_serde_round_trip() {
+        let mes = Message {
+            payload: Payload::String("data format error".to_string()),
+            command: Some(CommandType::DpQuery),
+            raw_command: None,
+            seq_nr: Some(7),
+            ret_code: Some(1),
+        };
+        let json = serde_json::to_string(&mes).unwrap();
+        assert_eq!(
+            json,
+            r#"{"payload":"data format error","command":"DpQuery","raw_command":null,"seq_nr":7,"ret_code":1}"#
+        );
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), mes);
+
+        let mes = Message::new(
+            crate::PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::Control,
+            Some(1),
+        );
+        let json = serde_json::to_string(&mes).unwrap();
+        assert_eq!(serde_json::from_str::<Message>(&json).unwrap(), mes);
+    }
+
+    #[test]
+    fn test_custom_framing() {
+        let mut mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        mp.set_framing([0, 0, 0x55, 0xBB], [0, 0, 0xBB, 0x55]);
+        let mes = Message::new(
+            Payload::String("".to_string()),
+            CommandType::HeartBeat,
+            Some(1),
+        );
+        let encoded = mp.encode(&mes, true).unwrap();
+        assert_eq!(encoded[..4], [0, 0, 0x55, 0xBB]);
+        assert_eq!(encoded[encoded.len() - 4..], [0, 0, 0xBB, 0x55]);
+        assert_eq!(mp.parse(&encoded).unwrap(), vec![mes]);
+
+        let standard = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        assert!(standard.parse(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let frame =
+            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+        let stream = [frame.clone(), frame.clone()].concat();
+
+        for split in 0..stream.len() {
+            let (messages, used) = mp.parse_stream(&stream[..split]).unwrap();
+            let complete_frames = split / frame.len();
+            assert_eq!(messages.len(), complete_frames);
+            assert_eq!(used, complete_frames * frame.len());
+        }
+        let (messages, used) = mp.parse_stream(&stream).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(used, stream.len());
+
+        assert!(mp.parse_stream(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_parse_stream_version_three_five() {
+        let mp = MessageParser::create("3.5", Some("bbe88b3f4106d354")).unwrap();
+        let mes = Message::new(
+            Payload::String("".to_string()),
+            CommandType::HeartBeat,
+            Some(1),
+        );
+        let frame = mp.encode(&mes, true).unwrap();
+
+        let (messages, used) = mp.parse_stream(&frame[..frame.len() - 1]).unwrap();
+        assert!(messages.is_empty());
+        assert_eq!(used, 0);
+        let (messages, used) = mp.parse_stream(&frame).unwrap();
+        assert_eq!(messages[0].command, Some(CommandType::HeartBeat));
+        assert_eq!(used, frame.len());
+    }
+
+    #[test]
+    fn test_parse_best_effort_keeps_valid_frames() {
+        let good = hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+        let bad = hex::decode("000055aa00000000000000090000000c00000000deadbeef0000aa55").unwrap();
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let packet = [good.clone(), bad, good].concat();
+        assert!(mp.parse(&packet).is_err());
+
+        let (messages, errors) = mp.parse_best_effort(&packet);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(errors[..], [ErrorKind::CRCError]));
+
+        let (messages, errors) = mp.parse_best_effort(&packet[..packet.len() - 4]);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            errors[..],
+            [ErrorKind::CRCError, ErrorKind::ParsingIncomplete]
+        ));
+    }
+
+    #[test]
+    fn test_parse_resync_skips_leading_garbage() {
+        let frame =
+            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+        let mp = MessageParser::create("3.3", None).unwrap();
+        // Noise, including a partial prefix, followed by the tail of an earlier frame
+        let noisy = [
+            b"\x00\x00\x55\x01\x02".to_vec(),
+            frame[20..].to_vec(),
+            frame.clone(),
+        ]
+        .concat();
+        assert!(mp.parse(&noisy).is_err());
+
+        let (messages, skipped) = mp.parse_resync(&noisy).unwrap();
+        assert_eq!(skipped, noisy.len() - frame.len());
+        assert_eq!(messages, mp.parse(&frame).unwrap());
+
+        assert_eq!(mp.parse_resync(&frame).unwrap().1, 0);
+        assert!(matches!(
+            mp.parse_resync(b"garbage"),
+            Err(ErrorKind::ParseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_rejects_too_short_lengths() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        // The length does not cover the suffix
+        let frame = hex::decode("000055aa00000000000000090000000200000000aa55").unwrap();
+        assert!(matches!(mp.parse(&frame), Err(ErrorKind::ParseError(_))));
+        // A return code, but no room for the CRC
+        let frame = hex::decode("000055aa0000000000000009000000080000000100000000aa55").unwrap();
+        assert!(matches!(mp.parse(&frame), Err(ErrorKind::ParseError(_))));
+    }
+
+    /// The same input as the fuzz target in fuzz/, but deterministic: every byte of valid frames
+    /// is corrupted in turn, the frames are cut at every length and pseudo random bytes are
+    /// parsed. None of it may panic.
+    #[test]
+    fn test_parse_never_panics() {
+        fn parse_all(mp: &MessageParser, buf: &[u8]) {
+            let _ = mp.parse(buf);
+            let _ = mp.parse_best_effort(buf);
+            let _ = mp.parse_stream(buf);
+            let _ = mp.parse_resync(buf);
+        }
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            // xorshift32
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for version in ["3.1", "3.3", "3.4", "3.5"] {
+            let mp = MessageParser::create(version, Some("bbe88b3f4106d354")).unwrap();
+            let mes = Message::new(
+                PayloadStruct::builder("002004265ccf7fb1b659")
+                    .dp("1", true)
+                    .build(),
+                CommandType::DpQuery,
+                Some(1),
+            );
+            let frame = mp.encode(&mes, true).unwrap();
+            for len in 0..frame.len() {
+                parse_all(&mp, &frame[..len]);
+            }
+            for i in 0..frame.len() {
+                for value in [0x00, 0x03, 0xff, next() as u8] {
+                    let mut corrupt = frame.clone();
+                    corrupt[i] = value;
+                    parse_all(&mp, &corrupt);
+                }
+            }
+            for _ in 0..200 {
+                let len = next() as usize % 128;
+                let random: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+                parse_all(&mp, &[&mp.prefix[..], &random].concat());
+            }
+        }
+    }
+
     #[test]
     fn test_parse_messages_with_payload() {
         let packet = hex::decode("000055aa00000000000000070000005b00000000332e33d8bab8946c604148a45c15326ed3b99d683695a73c624e75a5aaa31f4061f5b99033e6d01f0b0abf9dbc76b2a54eb4bf60976b1dc496169db9e5a3fd627f2c3d9c4744585e471b6a2fc479ca01f7e18e0000aa55").unwrap();
@@ -382,13 +1452,17 @@ mod tests {
         dps.insert("1".to_string(), json!(true));
         let expected = Message {
             command: Some(CommandType::Control),
+            raw_command: None,
             payload: Payload::Struct(PayloadStruct {
                 dev_id: "46052834d8f15b92e53b".to_string(),
                 gw_id: None,
+                cid: None,
                 uid: None,
                 t: None,
+                s: None,
                 dp_id: None,
                 dps: Some(dps),
+                extra: HashMap::new(),
             }),
             seq_nr: Some(0),
             ret_code: Some(0),
@@ -399,12 +1473,31 @@ mod tests {
         assert_eq!(buf, &[] as &[u8]);
     }
 
+    #[test]
+    fn test_encode_parsed_reply_round_trip() {
+        // Replies have a return code, re-encoding them must give the same frame
+        let packet = hex::decode("000055aa00000000000000070000005b00000000332e33d8bab8946c604148a45c15326ed3b99d683695a73c624e75a5aaa31f4061f5b99033e6d01f0b0abf9dbc76b2a54eb4bf60976b1dc496169db9e5a3fd627f2c3d9c4744585e471b6a2fc479ca01f7e18e0000aa55").unwrap();
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let parsed = mp.parse(&packet).unwrap();
+        assert_eq!(parsed[0].ret_code, Some(0));
+        let encoded = mp.encode(&parsed[0], true).unwrap();
+        assert_eq!(encoded, packet);
+        assert_eq!(mp.parse(&encoded).unwrap(), parsed);
+
+        let packet =
+            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55").unwrap();
+        let mp = MessageParser::create("3.1", None).unwrap();
+        let parsed = mp.parse(&packet).unwrap();
+        assert_eq!(mp.encode(&parsed[0], false).unwrap(), packet);
+    }
+
     #[test]
     fn test_parse_data_format_error() {
         let packet =
             hex::decode("000055aa00000000000000070000003b00000001332e33d504910232d355a59ed1f6ed1f4a816a1e8e30ed09987c020ae45d72c70592bb233c79c43a5b9ae49b6ead38725deb520000aa55").unwrap();
         let expected = Message {
             command: Some(CommandType::Control),
+            raw_command: None,
             payload: Payload::String("data format error".to_string()),
             seq_nr: Some(0),
             ret_code: Some(1),
@@ -413,21 +1506,27 @@ mod tests {
         let (buf, messages) = mp.parse_messages(&packet).unwrap();
         assert_eq!(messages[0], expected);
         assert_eq!(buf, &[] as &[u8]);
+        assert!(matches!(
+            messages[0].device_error(),
+            Some(ErrorKind::DeviceError { code: 1, message }) if message == "data format error"
+        ));
     }
 
     #[test]
     fn test_parse_double_messages() {
         let packet =
-            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55000055aa000000000000000a0000000c00000000b051ab030000aa55").unwrap();
-        let expected = vec![
+            hex::decode("000055aa00000000000000090000000c00000000b051ab030000aa55000055aa000000000000000a0000000c0000000089dc97c60000aa55").unwrap();
+        let expected = [
             Message {
                 command: Some(CommandType::HeartBeat),
+                raw_command: None,
                 payload: Payload::String("".to_string()),
                 seq_nr: Some(0),
                 ret_code: Some(0),
             },
             Message {
                 command: Some(CommandType::DpQuery),
+                raw_command: None,
                 payload: Payload::String("".to_string()),
                 seq_nr: Some(0),
                 ret_code: Some(0),
@@ -440,6 +1539,33 @@ mod tests {
         assert_eq!(buf, &[] as &[u8]);
     }
 
+    #[test]
+    fn test_parse_double_encrypted_messages() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let first = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::Control,
+            Some(1),
+        );
+        let second = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", false)
+                .dp("2", "a longer value than in the first message")
+                .build(),
+            CommandType::Status,
+            Some(2),
+        );
+        let packet = [
+            mp.encode(&first, true).unwrap(),
+            mp.encode(&second, true).unwrap(),
+        ]
+        .concat();
+        let messages = mp.parse(&packet).unwrap();
+        assert_eq!(messages, vec![first, second]);
+    }
+
     #[test]
     fn test_encode_with_and_without_encryption_and_version_three_one() {
         let mut dps = HashMap::new();
@@ -448,13 +1574,17 @@ mod tests {
         let payload = Payload::Struct(PayloadStruct {
             dev_id: "002004265ccf7fb1b659".to_string(),
             gw_id: None,
+            cid: None,
             uid: None,
             t: None,
+            s: None,
             dp_id: None,
             dps: Some(dps),
+            extra: HashMap::new(),
         });
         let mes = Message {
             command: Some(CommandType::DpQuery),
+            raw_command: None,
             payload,
             seq_nr: Some(0),
             ret_code: Some(0),
@@ -473,13 +1603,17 @@ mod tests {
         let payload = Payload::Struct(PayloadStruct {
             dev_id: "002004265ccf7fb1b659".to_string(),
             gw_id: None,
+            cid: None,
             uid: None,
             t: None,
+            s: None,
             dp_id: None,
             dps: Some(dps),
+            extra: HashMap::new(),
         });
         let mes = Message {
             command: Some(CommandType::DpQuery),
+            raw_command: None,
             payload,
             seq_nr: Some(0),
             ret_code: Some(0),
@@ -491,4 +1625,167 @@ mod tests {
         // Always encrypt 3.3, no matter what the flag is
         assert_eq!(encrypted, unencrypted);
     }
+
+    #[test]
+    fn test_encode_and_parse_version_three_two() {
+        let mut dps = HashMap::new();
+        dps.insert("1".to_string(), json!(true));
+        let mes = Message::new(
+            Payload::Struct(PayloadStruct {
+                dev_id: "002004265ccf7fb1b659".to_string(),
+                gw_id: None,
+                cid: None,
+                uid: None,
+                t: None,
+                s: None,
+                dp_id: None,
+                dps: Some(dps),
+                extra: HashMap::new(),
+            }),
+            CommandType::Control,
+            Some(1),
+        );
+        let parser = MessageParser::create("3.2", None).unwrap();
+        let three_three = MessageParser::create("3.3", None).unwrap();
+
+        let encoded = parser.encode(&mes, false).unwrap();
+        // Same encryption as 3.3, only the version and the MD5 in the header differ
+        let reference = three_three.encode(&mes, false).unwrap();
+        assert_eq!(&encoded[16..19], b"3.2");
+        assert_eq!(
+            encoded[31..encoded.len() - 8],
+            reference[31..reference.len() - 8]
+        );
+
+        let decoded = parser.parse(&encoded).unwrap();
+        assert_eq!(decoded[0].payload, mes.payload);
+    }
+
+    #[test]
+    fn test_encode_and_parse_with_session_key_version_three_four() {
+        let mut dps = HashMap::new();
+        dps.insert("1".to_string(), json!(true));
+        let mes = Message::new(
+            Payload::Struct(PayloadStruct {
+                dev_id: "002004265ccf7fb1b659".to_string(),
+                gw_id: None,
+                cid: None,
+                uid: None,
+                t: None,
+                s: None,
+                dp_id: None,
+                dps: Some(dps),
+                extra: HashMap::new(),
+            }),
+            CommandType::ControlNew,
+            Some(1),
+        );
+        let mut parser = MessageParser::create("3.4", Some("bbe88b3f4106d354")).unwrap();
+        parser.set_session_key(b"0123456789abcdef".to_vec());
+        let encoded = parser.encode(&mes, true).unwrap();
+        assert_eq!(parser.parse(&encoded).unwrap()[0], mes);
+
+        // A parser without the session key can not verify the HMAC
+        let no_session = MessageParser::create("3.4", Some("bbe88b3f4106d354")).unwrap();
+        assert!(matches!(
+            no_session.parse(&encoded),
+            Err(ErrorKind::CRCError)
+        ));
+    }
+
+    #[test]
+    fn test_session_key_negotiation() {
+        for version in ["3.4", "3.5"] {
+            let client = MessageParser::create(version, Some("bbe88b3f4106d354")).unwrap();
+            let device = client.clone();
+            let local_nonce = b"0123456789abcdef";
+            let remote_nonce = b"fedcba9876543210";
+
+            let start = client.encode_session_key_start(local_nonce, 1).unwrap();
+            let start = complete(device.parse_frames(&start)).unwrap();
+            assert_eq!(start[0].command, 3);
+            assert_eq!(start[0].payload, local_nonce.to_vec());
+
+            let mut response = remote_nonce.to_vec();
+            response.extend(device.cipher.hmac(local_nonce).unwrap());
+            let response = device
+                .encode_frame(
+                    1,
+                    CommandType::SessKeyNegResp,
+                    None,
+                    device.encrypt_payload(&response).unwrap(),
+                )
+                .unwrap();
+            let (finish, session_key) = client
+                .encode_session_key_finish(local_nonce, &response, 1)
+                .unwrap();
+            let finish = complete(device.parse_frames(&finish)).unwrap();
+            assert_eq!(finish[0].command, 5);
+            assert_eq!(finish[0].payload, device.cipher.hmac(remote_nonce).unwrap());
+            assert_eq!(
+                session_key,
+                device
+                    .cipher
+                    .derive_session_key(local_nonce, remote_nonce)
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_session_key_negotiation_with_bad_hmac() {
+        let client = MessageParser::create("3.4", Some("bbe88b3f4106d354")).unwrap();
+        let mut response = b"fedcba9876543210".to_vec();
+        response.extend(client.cipher.hmac(b"not the local nonce").unwrap());
+        let response = client
+            .encode_frame(
+                1,
+                CommandType::SessKeyNegResp,
+                None,
+                client.cipher.encrypt(&response).unwrap(),
+            )
+            .unwrap();
+        assert!(matches!(
+            client.encode_session_key_finish(b"0123456789abcdef", &response, 1),
+            Err(ErrorKind::SessionKeyNegotiationError)
+        ));
+    }
+
+    #[test]
+    fn test_encode_and_parse_version_three_five() {
+        let mes = Message {
+            command: Some(CommandType::Status),
+            raw_command: None,
+            payload: Payload::String("".to_string()),
+            seq_nr: Some(3),
+            ret_code: Some(0),
+        };
+        let parser = MessageParser::create("3.5", Some("bbe88b3f4106d354")).unwrap();
+        let encoded = parser.encode(&mes, true).unwrap();
+        assert_eq!(encoded[..6], [0x00, 0x00, 0x66, 0x99, 0x00, 0x00]);
+        assert_eq!(encoded[encoded.len() - 4..], [0x00, 0x00, 0x99, 0x66]);
+        let (buf, messages) = parser.parse_messages(&encoded).unwrap();
+        assert_eq!(messages[0], mes);
+        assert_eq!(buf, &[] as &[u8]);
+
+        // Tampering with the header is detected by the GCM tag
+        let mut tampered = encoded.clone();
+        tampered[9] = 4;
+        assert!(matches!(parser.parse(&tampered), Err(ErrorKind::CRCError)));
+    }
+
+    #[test]
+    fn test_message_dps() {
+        let packet = hex::decode("000055aa00000000000000070000005b00000000332e33d8bab8946c604148a45c15326ed3b99d683695a73c624e75a5aaa31f4061f5b99033e6d01f0b0abf9dbc76b2a54eb4bf60976b1dc496169db9e5a3fd627f2c3d9c4744585e471b6a2fc479ca01f7e18e0000aa55").unwrap();
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let messages = mp.parse(&packet).unwrap();
+        assert_eq!(messages[0].dps().unwrap().get("1"), Some(&json!(true)));
+
+        let mes = Message::new(
+            Payload::String("data format error".to_string()),
+            CommandType::Control,
+            None,
+        );
+        assert_eq!(mes.dps(), None);
+    }
 }
@@ -0,0 +1,212 @@
+//! # TuyaDevicePool
+//! Keeps the connections to devices open between calls, for applications that talk to many
+//! devices often. A connection is checked out together with its TuyaDevice and is returned to the
+//! pool when the PooledDevice is dropped. Connections that have been idle longer than max_idle are
+//! closed.
+//!
+//! ## Example
+//! ```no_run
+//! # use rust_tuyapi::{Result, TuyaDevice, TuyaDevicePool};
+//! # use std::net::IpAddr;
+//! # use std::str::FromStr;
+//! # use std::time::Duration;
+//! # fn main() -> Result<()> {
+//! let pool = TuyaDevicePool::new(Duration::from_secs(30));
+//! let device = TuyaDevice::create("3.4", Some("fedcba9876543210"),
+//!     IpAddr::from_str("192.168.0.123").unwrap())?
+//!     .with_ids("123456789abcdef", None);
+//! // Both calls use the same connection and session key
+//! pool.checkout(&device)?.set_value("1", true.into(), 1)?;
+//! pool.checkout(&device)?.set_value("1", false.into(), 2)?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::tuyadevice::{Connection, TuyaDevice};
+use crate::{log_addr, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+pub struct TuyaDevicePool {
+    max_idle: Duration,
+    idle: Mutex<HashMap<SocketAddr, (Connection, Instant)>>,
+}
+
+impl TuyaDevicePool {
+    pub fn new(max_idle: Duration) -> TuyaDevicePool {
+        TuyaDevicePool {
+            max_idle,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Give device a connection from the pool, or open a new one if there is none for its
+    /// address. All calls made through the returned PooledDevice use that connection. If
+    /// connect() has already been called on the device, that connection is used and left open.
+    pub fn checkout<'a>(&'a self, device: &'a TuyaDevice) -> Result<PooledDevice<'a>> {
+        self.evict_idle();
+        let mut connection = device.lock_connection();
+        let owned = connection.is_none();
+        if owned {
+            *connection = Some(match self.lock_idle().remove(&device.addr()) {
+                Some((pooled, _)) => {
+                    debug!("Reusing the connection to {}", log_addr(&device.addr()));
+                    pooled
+                }
+                None => device.open_connection(0, None)?,
+            });
+        }
+        Ok(PooledDevice {
+            pool: self,
+            device,
+            owned,
+        })
+    }
+
+    /// Close the connections that have been idle longer than max_idle. This is also done on every
+    /// checkout.
+    pub fn evict_idle(&self) {
+        let max_idle = self.max_idle;
+        self.lock_idle().retain(|addr, (connection, since)| {
+            let keep = since.elapsed() <= max_idle;
+            if !keep {
+                debug!("Closing the idle connection to {}", log_addr(addr));
+                close(addr, connection);
+            }
+            keep
+        });
+    }
+
+    /// The number of idle connections in the pool.
+    pub fn len(&self) -> usize {
+        self.lock_idle().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock_idle().is_empty()
+    }
+
+    /// Close all idle connections.
+    pub fn clear(&self) {
+        for (addr, (connection, _)) in self.lock_idle().drain() {
+            close(&addr, &connection);
+        }
+    }
+
+    fn lock_idle(&self) -> MutexGuard<'_, HashMap<SocketAddr, (Connection, Instant)>> {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for TuyaDevicePool {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+fn close(addr: &SocketAddr, connection: &Connection) {
+    if let Err(e) = connection.transport.teardown() {
+        warn!("Closing the connection to {} failed: {}", log_addr(addr), e);
+    }
+}
+
+/// A TuyaDevice with a connection from a TuyaDevicePool. Dropping it returns the connection to
+/// the pool.
+pub struct PooledDevice<'a> {
+    pool: &'a TuyaDevicePool,
+    device: &'a TuyaDevice,
+    owned: bool,
+}
+
+impl Deref for PooledDevice<'_> {
+    type Target = TuyaDevice;
+
+    fn deref(&self) -> &TuyaDevice {
+        self.device
+    }
+}
+
+impl Drop for PooledDevice<'_> {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        let connection = self.device.lock_connection().take();
+        if let Some(connection) = connection {
+            let mut idle = self.pool.lock_idle();
+            if let Some((old, _)) = idle.insert(self.device.addr(), (connection, Instant::now())) {
+                close(&self.device.addr(), &old);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+    use crate::mesparse::{CommandType, Message, MessageParser};
+    use crate::transports::Transport;
+    use crate::PayloadStruct;
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, TcpListener};
+    use std::str::FromStr;
+    use std::thread;
+
+    /// A fake device that accepts a single connection and answers `requests` requests on it.
+    fn spawn_device(requests: usize) -> (TuyaDevice, thread::JoinHandle<()>) {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let reply = mp.encode(&reply, true).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for _ in 0..requests {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        let device = TuyaDevice::create_with_mp(
+            mp,
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        )
+        .with_ids("002004265ccf7fb1b659", None);
+        (device, server)
+    }
+
+    #[test]
+    fn connection_is_reused_between_checkouts() {
+        let (device, server) = spawn_device(2);
+        let pool = TuyaDevicePool::new(Duration::from_secs(60));
+        assert!(pool.is_empty());
+        // The fake device only accepts one connection
+        pool.checkout(&device).unwrap().status().unwrap();
+        assert_eq!(pool.len(), 1);
+        pool.checkout(&device).unwrap().status().unwrap();
+        assert_eq!(pool.len(), 1);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn idle_connections_are_evicted() {
+        let (device, server) = spawn_device(1);
+        let pool = TuyaDevicePool::new(Duration::ZERO);
+        pool.checkout(&device).unwrap().status().unwrap();
+        assert_eq!(pool.len(), 1);
+        thread::sleep(Duration::from_millis(10));
+        pool.evict_idle();
+        assert!(pool.is_empty());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! # Recorder
+//! Records the raw bytes a TuyaDevice sends and receives to a file, for analysing intermittent
+//! device issues offline. Recording is enabled with TuyaDevice::with_recorder(), without it
+//! nothing is recorded.
+//!
+//! Each record in the file is laid out as, with all integers big endian:
+//!
+//! | bytes | content                                       |
+//! |-------|-----------------------------------------------|
+//! | 1     | direction, 0 for sent and 1 for received      |
+//! | 8     | timestamp, milliseconds since the UNIX epoch  |
+//! | 4     | sequence number of the call                   |
+//! | 4     | length of the data                            |
+//! | n     | the data, as written to or read from the wire |
+//!
+//! ## Example
+//! ```no_run
+//! # use rust_tuyapi::mesparse::MessageParser;
+//! # use rust_tuyapi::record::{read_records, Recorder};
+//! # use rust_tuyapi::{Payload, Result, TuyaDevice};
+//! # use std::fs::File;
+//! # use std::net::IpAddr;
+//! # use std::str::FromStr;
+//! # fn main() -> Result<()> {
+//! let device = TuyaDevice::create("3.3", Some("fedcba9876543210"),
+//!     IpAddr::from_str("192.168.0.123").unwrap())?
+//!     .with_recorder(Recorder::create("device.rec")?);
+//! device.get(Payload::query("123456789abcdef", "123456789abcdef"), 1)?;
+//!
+//! // Later, replay the recorded frames
+//! let mp = MessageParser::create("3.3", Some("fedcba9876543210"))?;
+//! for record in read_records(File::open("device.rec")?)? {
+//!     println!("{:?} ({}): {:?}", record.direction, record.seq_nr, record.parse(&mp));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::error::ErrorKind;
+use crate::mesparse::{Message, MessageParser};
+use crate::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const HEADER_LEN: usize = 1 + 8 + 4 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One buffer sent to or received from a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    pub timestamp: SystemTime,
+    pub seq_nr: u32,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    /// Parse the recorded bytes with the MessageParser of the device. For 3.4 and 3.5 only the
+    /// session key negotiation can be parsed with the MessageParser created from the local key,
+    /// the rest is encrypted with the session key.
+    pub fn parse(&self, mp: &MessageParser) -> Result<Vec<Message>> {
+        mp.parse(&self.data)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let millis = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.data.len());
+        buf.push(match self.direction {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        });
+        buf.extend(millis.to_be_bytes());
+        buf.extend(self.seq_nr.to_be_bytes());
+        buf.extend((self.data.len() as u32).to_be_bytes());
+        buf.extend(&self.data);
+        buf
+    }
+}
+
+/// Appends records to a file. It is shared by all calls on a TuyaDevice, each record is written
+/// with a single write so that records of concurrent calls are not mixed.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Open the file at path for recording, records are appended if it already exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Recorder> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Recorder {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, seq_nr: u32, data: &[u8]) -> Result<()> {
+        let record = Record {
+            direction,
+            timestamp: SystemTime::now(),
+            seq_nr,
+            data: data.to_vec(),
+        };
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(file.write_all(&record.encode())?)
+    }
+}
+
+/// Read all records from a file written by a Recorder. A record that was cut short, e.g. when
+/// the recording process was killed, fails with ErrorKind::ParsingIncomplete.
+pub fn read_records(mut reader: impl Read) -> Result<Vec<Record>> {
+    let mut records = vec![];
+    loop {
+        let mut header = [0; HEADER_LEN];
+        match reader.read_exact(&mut header[..1]) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(e) => return Err(e.into()),
+        }
+        reader
+            .read_exact(&mut header[1..])
+            .map_err(incomplete_record)?;
+        let direction = match header[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            _ => return Err(ErrorKind::ParseError(nom::error::ErrorKind::Tag)),
+        };
+        let millis = u64::from_be_bytes(header[1..9].try_into().unwrap());
+        let seq_nr = u32::from_be_bytes(header[9..13].try_into().unwrap());
+        let len = u32::from_be_bytes(header[13..17].try_into().unwrap());
+        // Read no more than is there, a corrupt length must not allocate gigabytes up front
+        let mut data = vec![];
+        reader.by_ref().take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len as usize {
+            return Err(ErrorKind::ParsingIncomplete);
+        }
+        records.push(Record {
+            direction,
+            timestamp: UNIX_EPOCH + Duration::from_millis(millis),
+            seq_nr,
+            data,
+        });
+    }
+}
+
+fn incomplete_record(e: io::Error) -> ErrorKind {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => ErrorKind::ParsingIncomplete,
+        _ => ErrorKind::TcpError(e),
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+    use crate::mesparse::CommandType;
+    use crate::Payload;
+
+    #[test]
+    fn record_and_read_back() {
+        let path = std::env::temp_dir().join(format!("tuyapi-record-{}.rec", std::process::id()));
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let query = Message::new(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            CommandType::DpQuery,
+            Some(7),
+        );
+        let sent = mp.encode(&query, true).unwrap();
+        {
+            let recorder = Recorder::create(&path).unwrap();
+            recorder.record(Direction::Sent, 7, &sent).unwrap();
+            recorder.record(Direction::Received, 7, b"").unwrap();
+        }
+
+        let records = read_records(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].seq_nr, 7);
+        assert_eq!(records[0].data, sent);
+        assert_eq!(records[0].parse(&mp).unwrap()[0].payload, query.payload);
+        assert_eq!(records[1].direction, Direction::Received);
+        assert!(records[1].data.is_empty());
+    }
+
+    #[test]
+    fn read_truncated_record() {
+        let record = Record {
+            direction: Direction::Received,
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            seq_nr: 3,
+            data: vec![1, 2, 3, 4],
+        };
+        let encoded = record.encode();
+        assert_eq!(read_records(&encoded[..]).unwrap(), vec![record]);
+        assert!(matches!(
+            read_records(&encoded[..encoded.len() - 1]),
+            Err(ErrorKind::ParsingIncomplete)
+        ));
+
+        // A corrupt length is not allocated before the data is read
+        let mut corrupt = encoded.clone();
+        corrupt[13..17].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            read_records(&corrupt[..]),
+            Err(ErrorKind::ParsingIncomplete)
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+//! # Scaled dps
+//! Metering devices report their readings as integers that have to be divided by a scale, e.g.
+//! a voltage of 231.5 V is reported as 2315. A DeviceProfile maps the dp ids of a device to
+//! their divisors, so that the readings can be read in their units.
+//!
+//! The dp ids and scales are device specific. DeviceProfile::energy_plug() has the ones most
+//! Tuya energy metering plugs use, other devices need their own profile. The scales of a device
+//! can usually be found in the Tuya IoT platform, where a scale of n means a divisor of 10^n.
+//!
+//! ## Example
+//! ```
+//! # use rust_tuyapi::scale::DeviceProfile;
+//! # use rust_tuyapi::{Payload, PayloadStruct};
+//! let profile = DeviceProfile::energy_plug();
+//! let reply = PayloadStruct::builder("002004265ccf7fb1b659").dp("20", 2315).build();
+//! if let Payload::Struct(reply) = reply {
+//!     assert_eq!(profile.value(&reply, "20"), Some(231.5));
+//! }
+//! ```
+use crate::error::ErrorKind;
+use crate::{DpsValue, PayloadStruct, Result};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// A numeric dps value divided by divisor. Returns None if the value is not a number.
+pub fn scale_dp(value: &serde_json::Value, divisor: f64) -> Option<f64> {
+    let number = match DpsValue::from(value.clone()) {
+        DpsValue::Int(i) => i as f64,
+        DpsValue::Float(f) => f,
+        // Integers above i64::MAX
+        DpsValue::Raw(value) => value.as_f64()?,
+        DpsValue::Bool(_) | DpsValue::Str(_) => return None,
+    };
+    Some(number / divisor)
+}
+
+/// Maps the dp ids of a device to the divisors of their values. Created with with_scale(), or
+/// from a json object like `{"19": 10, "20": 10}`. Dps without a divisor are read unscaled.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct DeviceProfile {
+    divisors: HashMap<String, f64>,
+}
+
+impl DeviceProfile {
+    pub fn new() -> DeviceProfile {
+        DeviceProfile::default()
+    }
+
+    /// The dps of most energy metering plugs: the current in mA on dp 18, the power in tenths of
+    /// a W on dp 19 and the voltage in tenths of a V on dp 20. They are read in A, W and V.
+    pub fn energy_plug() -> DeviceProfile {
+        DeviceProfile::new()
+            .with_scale("18", 1000.0)
+            .with_scale("19", 10.0)
+            .with_scale("20", 10.0)
+    }
+
+    pub fn from_json(json: &str) -> Result<DeviceProfile> {
+        let profile: DeviceProfile = serde_json::from_str(json)?;
+        match profile
+            .divisors
+            .iter()
+            .find(|(_, divisor)| **divisor == 0.0)
+        {
+            Some((dp, _)) => Err(ErrorKind::InvalidScale(dp.clone())),
+            None => Ok(profile),
+        }
+    }
+
+    /// Divide the values of dp by divisor. A divisor of 0 is ignored with a warning.
+    pub fn with_scale(mut self, dp: &str, divisor: f64) -> DeviceProfile {
+        if divisor == 0.0 {
+            warn!("Ignoring the divisor 0 of dp {}", dp);
+            return self;
+        }
+        self.divisors.insert(dp.to_string(), divisor);
+        self
+    }
+
+    /// The divisor of dp, None if it is read unscaled.
+    pub fn divisor(&self, dp: &str) -> Option<f64> {
+        self.divisors.get(dp).copied()
+    }
+
+    /// The value of dp in payload, divided by its divisor. Returns None if the dp is missing or
+    /// not a number.
+    pub fn value(&self, payload: &PayloadStruct, dp: &str) -> Option<f64> {
+        let value: serde_json::Value = payload.dp_value(dp)?.into();
+        scale_dp(&value, self.divisor(dp).unwrap_or(1.0))
+    }
+
+    /// The values of the dps in payload that have a divisor. Dps that are not numbers are left
+    /// out.
+    pub fn values(&self, payload: &PayloadStruct) -> HashMap<String, f64> {
+        self.divisors
+            .keys()
+            .filter_map(|dp| Some((dp.clone(), self.value(payload, dp)?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn scale_numbers_only() {
+        assert_eq!(scale_dp(&json!(2315), 10.0), Some(231.5));
+        assert_eq!(scale_dp(&json!(1.5), 0.5), Some(3.0));
+        assert_eq!(scale_dp(&json!(u64::MAX), 1.0), Some(u64::MAX as f64));
+        assert_eq!(scale_dp(&json!("2315"), 10.0), None);
+        assert_eq!(scale_dp(&json!(true), 10.0), None);
+    }
+
+    #[test]
+    fn energy_plug_values() {
+        let reply = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .dp("18", 1234)
+            .dp("19", 2855)
+            .dp("20", 2315)
+            .build();
+        let reply = match reply {
+            crate::Payload::Struct(reply) => reply,
+            other => panic!("unexpected payload {:?}", other),
+        };
+        let profile = DeviceProfile::energy_plug();
+        assert_eq!(profile.value(&reply, "18"), Some(1.234));
+        assert_eq!(profile.value(&reply, "1"), None);
+        assert_eq!(profile.value(&reply, "17"), None);
+        assert_eq!(
+            profile.values(&reply),
+            HashMap::from([
+                ("18".to_string(), 1.234),
+                ("19".to_string(), 285.5),
+                ("20".to_string(), 231.5),
+            ])
+        );
+    }
+
+    #[test]
+    fn profile_from_json() {
+        let profile = DeviceProfile::from_json(r#"{"19": 10, "20": 10.0}"#).unwrap();
+        assert_eq!(profile.divisor("19"), Some(10.0));
+        assert_eq!(profile.divisor("1"), None);
+        assert!(matches!(
+            DeviceProfile::from_json(r#"{"19": 0}"#),
+            Err(ErrorKind::InvalidScale(dp)) if dp == "19"
+        ));
+        assert_eq!(
+            DeviceProfile::new().with_scale("19", 0.0),
+            DeviceProfile::new()
+        );
+    }
+}
@@ -1,36 +1,111 @@
 //! # TuyaTransports
-//! The TuyaTransport trait abstracts Tcp or Udp communication.
+//! The TuyaTransport trait abstracts Tcp or Udp communication, and can be implemented for other
+//! channels. With the `async` feature, the AsyncTuyaTransport trait does the same for the socket
+//! types of an async runtime.
 use crate::error::ErrorKind;
 use crate::Result;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io;
 use std::io::prelude::*;
 use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
 use std::time::Duration;
 
+/// How to reach a device. TCP takes the device port. UDP takes the local port to bind, 0 lets the
+/// OS pick one, and the remote port of the device. Custom is used by devices created with
+/// TuyaDevice::create_with_custom_transport().
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Transport {
     TCP(u16),
-    UDP(u16),
+    UDP { local: u16, remote: u16 },
+    Custom,
 }
 
-pub(crate) trait TuyaTransport {
-    fn setup(&self, addr: SocketAddr) -> Result<()>;
+/// Socket options applied by TuyaTransport::setup(). The default disables Nagle's algorithm, so
+/// the small frames are sent right away, and leaves TCP keepalive off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportOptions {
+    /// Set TCP_NODELAY on TCP connections.
+    pub nodelay: bool,
+    /// Enable TCP keepalive, with probes sent after the connection has been idle this long. It
+    /// keeps NAT entries of persistent connections alive. The idle time is only set on Linux,
+    /// Android, FreeBSD and Apple platforms, other Unix platforms use the system default and
+    /// other platforms ignore it.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for TransportOptions {
+    fn default() -> TransportOptions {
+        TransportOptions {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+/// The byte channel a TuyaDevice talks to a device over. It is implemented for TcpStream and
+/// UdpSocket, and can be implemented for other channels, e.g. a serial bridge or a TLS wrapped
+/// stream, which are then used with TuyaDevice::create_with_custom_transport(). The transport only
+/// moves bytes, the framing and encryption is done by the MessageParser.
+pub trait TuyaTransport {
+    /// Prepare the transport for communication with the device at addr, e.g. connect a socket
+    /// and apply the timeout to reads and writes. Options that do not apply to the transport are
+    /// ignored. Called once, before anything is sent.
+    fn setup(&self, addr: SocketAddr, timeout: Duration, options: &TransportOptions) -> Result<()>;
+    /// Change the timeout of reads and writes, e.g. to fit the time left of a total timeout.
+    /// Transports without timeouts can ignore it.
+    fn set_timeout(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+    /// Send the encoded frames in buf. Returns the number of bytes written.
     fn do_send(&mut self, buf: &[u8]) -> Result<usize>;
+    /// Read received bytes into buf and return how many were read. A reply may arrive in several
+    /// reads, the TuyaDevice keeps reading until it has whole frames. Return 0 when the channel
+    /// is closed, and ErrorKind::Timeout when nothing arrived within the timeout, see
+    /// io_error().
     fn do_read(&mut self, buf: &mut [u8]) -> Result<usize>;
+    /// Close the transport. Nothing is sent after this.
     fn teardown(&self) -> Result<()>;
+    /// The error to report when the channel is closed while data is required, e.g. during the
+    /// session key negotiation. A device that closes the connection instead of replying to a
+    /// request gives ErrorKind::EmptyReply.
     fn error(&self) -> ErrorKind;
 }
 
+/// Convert an io error of a transport, a timeout becomes ErrorKind::Timeout and a refused
+/// connection ErrorKind::ConnectionRefused. Depending on the platform a socket timeout is
+/// reported as WouldBlock or TimedOut. Other errors are kept as ErrorKind::TcpError.
+pub fn io_error(e: io::Error) -> ErrorKind {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ErrorKind::Timeout,
+        io::ErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+        _ => ErrorKind::TcpError(e),
+    }
+}
+
 impl TuyaTransport for TcpStream {
-    fn setup(&self, _addr: SocketAddr) -> Result<()> {
-        self.set_nodelay(true)?;
-        self.set_write_timeout(Some(Duration::new(2, 0)))?;
-        self.set_read_timeout(Some(Duration::new(2, 0)))?;
+    fn setup(
+        &self,
+        _addr: SocketAddr,
+        timeout: Duration,
+        options: &TransportOptions,
+    ) -> Result<()> {
+        self.set_nodelay(options.nodelay)?;
+        if let Some(idle) = options.keepalive {
+            set_keepalive(self, idle)?;
+        }
+        TuyaTransport::set_timeout(self, timeout)
+    }
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.set_write_timeout(Some(timeout))?;
+        self.set_read_timeout(Some(timeout))?;
         Ok(())
     }
     fn do_send(&mut self, buf: &[u8]) -> Result<usize> {
-        Ok(self.write(buf)?)
+        self.write(buf).map_err(io_error)
     }
     fn do_read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        Ok(self.read(buf)?)
+        self.read(buf).map_err(io_error)
     }
     fn teardown(&self) -> Result<()> {
         Ok(self.shutdown(Shutdown::Both)?)
@@ -41,17 +116,25 @@ impl TuyaTransport for TcpStream {
 }
 
 impl TuyaTransport for UdpSocket {
-    fn setup(&self, addr: SocketAddr) -> Result<()> {
+    fn setup(
+        &self,
+        addr: SocketAddr,
+        timeout: Duration,
+        _options: &TransportOptions,
+    ) -> Result<()> {
         self.connect(addr)?;
-        self.set_write_timeout(Some(Duration::new(2, 0)))?;
-        self.set_read_timeout(Some(Duration::new(2, 0)))?;
+        TuyaTransport::set_timeout(self, timeout)
+    }
+    fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        self.set_write_timeout(Some(timeout))?;
+        self.set_read_timeout(Some(timeout))?;
         Ok(())
     }
     fn do_send(&mut self, buf: &[u8]) -> Result<usize> {
-        Ok(self.send(buf)?)
+        self.send(buf).map_err(io_error)
     }
     fn do_read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        Ok(self.recv(buf)?)
+        self.recv(buf).map_err(io_error)
     }
     fn teardown(&self) -> Result<()> {
         Ok(())
@@ -60,3 +143,172 @@ impl TuyaTransport for UdpSocket {
         ErrorKind::BadUdpRead
     }
 }
+
+#[cfg(unix)]
+#[cfg_attr(
+    not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "ios"
+    )),
+    allow(unused_variables)
+)]
+fn set_keepalive(stream: &TcpStream, idle: Duration) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    fn setsockopt(
+        stream: &TcpStream,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> Result<()> {
+        // SAFETY: the fd is owned by the stream, and value outlives the call
+        let res = unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        match res {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error().into()),
+        }
+    }
+
+    setsockopt(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    setsockopt(
+        stream,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPIDLE,
+        keepalive_secs(idle),
+    )?;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    setsockopt(
+        stream,
+        libc::IPPROTO_TCP,
+        libc::TCP_KEEPALIVE,
+        keepalive_secs(idle),
+    )?;
+    Ok(())
+}
+
+/// The idle time in whole seconds, at least 1 since 0 is not accepted.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+    target_os = "ios"
+))]
+fn keepalive_secs(idle: Duration) -> libc::c_int {
+    idle.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int
+}
+
+#[cfg(not(unix))]
+fn set_keepalive(_stream: &TcpStream, _idle: Duration) -> Result<()> {
+    log::warn!("TCP keepalive is not supported on this platform, it is left off");
+    Ok(())
+}
+
+/// The async sibling of TuyaTransport, used by the AsyncTuyaDevice. The crate does not depend
+/// on any async runtime, instead this trait is implemented for the socket type of the runtime in
+/// use, e.g. a wrapper around tokio::net::TcpStream.
+#[cfg(feature = "async")]
+pub trait AsyncTuyaTransport: Sized + Send {
+    fn connect(addr: SocketAddr) -> impl Future<Output = Result<Self>> + Send;
+    fn do_send(&mut self, buf: &[u8]) -> impl Future<Output = Result<usize>> + Send;
+    fn do_read(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+    fn teardown(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    #[cfg(target_os = "linux")]
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn io_errors_are_classified() {
+        for kind in [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut] {
+            assert!(matches!(io_error(kind.into()), ErrorKind::Timeout));
+        }
+        assert!(matches!(
+            io_error(io::ErrorKind::ConnectionRefused.into()),
+            ErrorKind::ConnectionRefused
+        ));
+        assert!(matches!(
+            io_error(io::ErrorKind::BrokenPipe.into()),
+            ErrorKind::TcpError(e) if e.kind() == io::ErrorKind::BrokenPipe
+        ));
+    }
+
+    #[test]
+    fn udp_send_to_closed_port_is_refused() {
+        // Reserve a port and close it again, so nothing listens on it
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .setup(addr, Duration::from_secs(1), &TransportOptions::default())
+            .unwrap();
+        socket.do_send(b"ping").unwrap();
+        // The ICMP port unreachable of the send is reported by the next call
+        assert!(matches!(
+            socket.do_read(&mut [0; 16]),
+            Err(ErrorKind::ConnectionRefused)
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    fn getsockopt(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        value
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tcp_setup_applies_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(addr).unwrap();
+        let timeout = Duration::from_secs(1);
+
+        stream
+            .setup(addr, timeout, &TransportOptions::default())
+            .unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(getsockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 0);
+
+        let options = TransportOptions {
+            nodelay: false,
+            keepalive: Some(Duration::from_secs(30)),
+        };
+        stream.setup(addr, timeout, &options).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert_eq!(getsockopt(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(
+            getsockopt(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+            30
+        );
+    }
+}
@@ -5,22 +5,226 @@
 //!
 //! The TuyaDevice is the high level device communication API. To get in to the nitty gritty
 //! details, create a MessageParser.
-use crate::mesparse::{CommandType, Message, MessageParser};
-use crate::transports::{Transport, TuyaTransport};
-use crate::{Payload, Result};
-use log::{debug, info};
-use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+//!
+//! A TuyaDevice is created with TuyaDevice::builder(), or one of the create functions for the
+//! most common settings.
+//!
+//! Devices using protocol 3.4 and 3.5 negotiate a session key on every new connection, this is
+//! done by the TuyaDevice before the command is sent. They also use the ControlNew and DpQueryNew
+//! commands instead of Control and DpQuery.
+//!
+//! By default every call opens a new connection to the device and closes it when the reply is
+//! received. After calling connect(), the TuyaDevice instead keeps the connection open and
+//! reuses it until disconnect() is called. If the connection fails it is reopened once.
+//! For a shorter sequence of calls, session() returns a guard that keeps a connection open until
+//! it is dropped.
+//!
+//! Devices on the local network can be found with discover(), which listens for the UDP
+//! broadcasts the devices send.
+use crate::error::ErrorKind;
+use crate::mesparse::{CommandType, Message, MessageParser, TuyaVersion, UDP_KEY};
+use crate::record::{Direction, Recorder};
+use crate::transports::{io_error, Transport, TransportOptions, TuyaTransport};
+use crate::{log_addr, log_hex, now, Payload, PayloadBuilder, PayloadStruct, Result};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub struct TuyaDevice {
     mp: MessageParser,
     addr: SocketAddr,
     transport: Transport,
+    transport_options: TransportOptions,
+    timeout: Duration,
+    total_timeout: Option<Duration>,
+    retries: u32,
+    retry_delay: Duration,
+    dev_id: Option<String>,
+    gw_id: Option<String>,
+    check_sequence: bool,
+    auto_timestamp: bool,
+    encryption: HashMap<CommandType, bool>,
+    local_addr: Option<IpAddr>,
+    next_seq: AtomicU32,
+    connection: Mutex<Option<Connection>>,
+    recorder: Option<Recorder>,
+}
+
+/// An open transport to the device. For 3.4 and 3.5 it also holds the MessageParser with the
+/// session key negotiated for this connection.
+pub(crate) struct Connection {
+    pub(crate) transport: Box<dyn TuyaTransport + Send>,
+    session_mp: Option<MessageParser>,
+    /// Set by set_no_reply(), the reply it did not wait for is read before the next request.
+    unread_reply: bool,
+}
+
+/// Builder for a TuyaDevice. The version and ip address are required, and so is the local key
+/// for versions above 3.1. Everything else has a default: TCP on port 6668, a 2 second timeout
+/// and no retries.
+#[derive(Default)]
+pub struct TuyaDeviceBuilder {
+    version: Option<String>,
+    key: Option<String>,
+    ip: Option<IpAddr>,
+    transport: Option<Transport>,
+    transport_options: TransportOptions,
+    timeout: Option<Duration>,
+    total_timeout: Option<Duration>,
+    retries: Option<(u32, Duration)>,
+    dev_id: Option<String>,
+    gw_id: Option<String>,
+    check_sequence: bool,
+    auto_timestamp: bool,
+    encryption: HashMap<CommandType, bool>,
+    local_addr: Option<IpAddr>,
+    dp_query_with_header: bool,
 }
 
+impl TuyaDeviceBuilder {
+    pub fn version(mut self, version: &str) -> TuyaDeviceBuilder {
+        self.version = Some(version.to_string());
+        self
+    }
+
+    pub fn key(mut self, key: &str) -> TuyaDeviceBuilder {
+        self.key = Some(key.to_string());
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> TuyaDeviceBuilder {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn transport(mut self, transport: Transport) -> TuyaDeviceBuilder {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Socket options of the transport, see TuyaDevice::set_transport_options().
+    pub fn transport_options(mut self, options: TransportOptions) -> TuyaDeviceBuilder {
+        self.transport_options = options;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> TuyaDeviceBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The time a whole call may take, see TuyaDevice::set_total_timeout().
+    pub fn total_timeout(mut self, total_timeout: Duration) -> TuyaDeviceBuilder {
+        self.total_timeout = Some(total_timeout);
+        self
+    }
+
+    pub fn retries(mut self, count: u32, base_delay: Duration) -> TuyaDeviceBuilder {
+        self.retries = Some((count, base_delay));
+        self
+    }
+
+    /// The device id used by the convenience setters, e.g. set_value().
+    pub fn dev_id(mut self, dev_id: &str) -> TuyaDeviceBuilder {
+        self.dev_id = Some(dev_id.to_string());
+        self
+    }
+
+    /// The gateway id used by the convenience setters. Defaults to the device id.
+    pub fn gw_id(mut self, gw_id: &str) -> TuyaDeviceBuilder {
+        self.gw_id = Some(gw_id.to_string());
+        self
+    }
+
+    /// Fail calls where no reply has the sequence number of the request, see
+    /// TuyaDevice::with_sequence_check().
+    pub fn check_sequence(mut self, check: bool) -> TuyaDeviceBuilder {
+        self.check_sequence = check;
+        self
+    }
+
+    /// Set t of every command to the current time, see TuyaDevice::with_auto_timestamp().
+    pub fn auto_timestamp(mut self, auto: bool) -> TuyaDeviceBuilder {
+        self.auto_timestamp = auto;
+        self
+    }
+
+    /// The local address UDP sockets are bound to, see TuyaDevice::with_local_addr().
+    pub fn local_addr(mut self, local_addr: IpAddr) -> TuyaDeviceBuilder {
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Whether 3.1 messages with command are encrypted, see TuyaDevice::with_encryption().
+    pub fn encryption(mut self, command: CommandType, encrypt: bool) -> TuyaDeviceBuilder {
+        self.encryption.insert(command, encrypt);
+        self
+    }
+
+    /// Send the 3.3 header with DpQuery messages too, see
+    /// MessageParser::set_dp_query_with_header().
+    pub fn dp_query_with_header(mut self, with_header: bool) -> TuyaDeviceBuilder {
+        self.dp_query_with_header = with_header;
+        self
+    }
+
+    pub fn build(self) -> Result<TuyaDevice> {
+        let version = self.version.ok_or(ErrorKind::VersionError(
+            "Unknown".to_string(),
+            "Unknown".to_string(),
+        ))?;
+        let ip = self.ip.ok_or(ErrorKind::MissingAddressError)?;
+        let parsed_version = TuyaVersion::from_str(&version)?;
+        if self.key.is_none() && parsed_version.requires_key() {
+            return Err(ErrorKind::KeyRequiredForVersion(parsed_version));
+        }
+        let mut mp = MessageParser::create(&version, self.key.as_deref())?;
+        mp.set_dp_query_with_header(self.dp_query_with_header);
+        let transport = self.transport.unwrap_or(Transport::TCP(6668));
+        let mut device = TuyaDevice::create_with_mp(mp, ip, transport);
+        if let Some(timeout) = self.timeout {
+            device.set_timeout(timeout);
+        }
+        device.set_total_timeout(self.total_timeout);
+        device.set_transport_options(self.transport_options);
+        if let Some((count, base_delay)) = self.retries {
+            device = device.with_retries(count, base_delay);
+        }
+        if let Some(dev_id) = self.dev_id {
+            device = device.with_ids(&dev_id, self.gw_id.as_deref());
+        }
+        if let Some(local_addr) = self.local_addr {
+            device = device.with_local_addr(local_addr);
+        }
+        for (command, encrypt) in self.encryption {
+            device = device.with_encryption(command, encrypt);
+        }
+        Ok(device
+            .with_sequence_check(self.check_sequence)
+            .with_auto_timestamp(self.auto_timestamp))
+    }
+}
+
+/// How many times set_and_verify() reads the dps back before giving up.
+pub const VERIFY_ATTEMPTS: u32 = 3;
+/// The time set_and_verify() waits between reads, for devices that report new values slowly.
+pub const VERIFY_DELAY: Duration = Duration::from_millis(300);
+
 impl TuyaDevice {
+    pub fn builder() -> TuyaDeviceBuilder {
+        TuyaDeviceBuilder::default()
+    }
+
     pub fn create(ver: &str, key: Option<&str>, addr: IpAddr) -> Result<TuyaDevice> {
-        let mp = MessageParser::create(ver, key)?;
-        Ok(TuyaDevice::create_with_mp(mp, addr, Transport::TCP(6668)))
+        TuyaDevice::builder_with_key(ver, key, addr).build()
     }
 
     pub fn create_with_transport(
@@ -29,31 +233,368 @@ impl TuyaDevice {
         addr: IpAddr,
         transport: Transport,
     ) -> Result<TuyaDevice> {
-        let mp = MessageParser::create(ver, key)?;
-        Ok(TuyaDevice::create_with_mp(mp, addr, transport))
+        TuyaDevice::builder_with_key(ver, key, addr)
+            .transport(transport)
+            .build()
+    }
+
+    fn builder_with_key(ver: &str, key: Option<&str>, addr: IpAddr) -> TuyaDeviceBuilder {
+        let builder = TuyaDevice::builder().version(ver).ip(addr);
+        match key {
+            Some(key) => builder.key(key),
+            None => builder,
+        }
     }
 
     pub fn create_with_mp(mp: MessageParser, addr: IpAddr, transport: Transport) -> TuyaDevice {
-        match transport {
-            Transport::TCP(port) | Transport::UDP(port) => TuyaDevice {
-                mp,
-                addr: SocketAddr::new(addr, port),
-                transport,
-            },
+        let port = match transport {
+            Transport::TCP(port) | Transport::UDP { remote: port, .. } => port,
+            Transport::Custom => 0,
+        };
+        TuyaDevice {
+            mp,
+            addr: SocketAddr::new(addr, port),
+            transport,
+            transport_options: TransportOptions::default(),
+            timeout: Duration::new(2, 0),
+            total_timeout: None,
+            retries: 0,
+            retry_delay: Duration::new(0, 0),
+            dev_id: None,
+            gw_id: None,
+            check_sequence: false,
+            auto_timestamp: false,
+            encryption: HashMap::new(),
+            local_addr: None,
+            next_seq: AtomicU32::new(1),
+            connection: Mutex::new(None),
+            recorder: None,
         }
     }
 
+    /// Create a TuyaDevice that communicates over the given transport instead of a socket, e.g. a
+    /// fake transport in tests. The transport is set up, and for 3.4 and 3.5 the session key is
+    /// negotiated, before this returns. It is then used like the connection opened by connect().
+    /// Since the TuyaDevice can not reopen it, calls fail with ErrorKind::CustomTransportClosed
+    /// after disconnect().
+    pub fn create_with_custom_transport(
+        mp: MessageParser,
+        addr: SocketAddr,
+        transport: Box<dyn TuyaTransport + Send>,
+    ) -> Result<TuyaDevice> {
+        let mut device = TuyaDevice::create_with_mp(mp, addr.ip(), Transport::Custom);
+        device.addr = addr;
+        let connection = device.init_connection(transport, 0, None)?;
+        *device.connection_mut() = Some(connection);
+        Ok(device)
+    }
+
+    pub fn create_with_timeout(
+        ver: &str,
+        key: Option<&str>,
+        addr: IpAddr,
+        timeout: Duration,
+    ) -> Result<TuyaDevice> {
+        TuyaDevice::builder_with_key(ver, key, addr)
+            .timeout(timeout)
+            .build()
+    }
+
+    /// Set the timeout used when connecting to, writing to and reading from the device. The
+    /// default is 2 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set the socket options used when a connection is opened, e.g. TCP keepalive for
+    /// connections opened with connect(). The default sets nodelay and no keepalive.
+    pub fn set_transport_options(&mut self, options: TransportOptions) {
+        self.transport_options = options;
+    }
+
+    /// Limit the time a whole call may take, including connecting, the session key negotiation,
+    /// writing, reading and retries. Each of them still uses at most the timeout of
+    /// set_timeout(). A call that runs out of time fails with ErrorKind::Timeout. There is no
+    /// limit by default.
+    pub fn set_total_timeout(&mut self, total_timeout: Option<Duration>) {
+        self.total_timeout = total_timeout;
+    }
+
+    /// Retry failed calls up to count times. The first retry is done after base_delay and the
+    /// delay is doubled for every following retry. Only errors that may be transient are
+    /// retried, i.e. I/O errors, bad reads, incomplete replies and CRC errors. A connection that
+    /// is closed without a reply is not retried, since the device may have applied the command.
+    pub fn with_retries(mut self, count: u32, base_delay: Duration) -> TuyaDevice {
+        self.retries = count;
+        self.retry_delay = base_delay;
+        self
+    }
+
+    /// Set the dps of the device without waiting for its reply, e.g. for scenes where waiting for
+    /// devices that do not reply wastes time. Returns as soon as the message is written, so
+    /// errors reported by the device, like ErrorKind::DeviceError from set(), are missed. Failed
+    /// writes are not retried. On a connection opened with connect() the reply the device may
+    /// still send is read and dropped before the next request, waiting at most
+    /// UNREAD_REPLY_WAIT for it.
+    pub fn set_no_reply(&self, tuya_payload: Payload, seq_id: u32) -> Result<()> {
+        let mes = self.set_message(tuya_payload, seq_id);
+        let deadline = self.total_timeout.map(|total| Instant::now() + total);
+        let mut persistent = self.lock_connection();
+        match persistent.as_mut() {
+            Some(connection) => {
+                self.write_message(connection, &mes, seq_id, deadline)?;
+                connection.unread_reply = true;
+                Ok(())
+            }
+            None => {
+                drop(persistent);
+                let mut connection = self.open_connection(seq_id, deadline)?;
+                self.write_message(&mut connection, &mes, seq_id, deadline)?;
+                debug!("Shutting down connection ({})", seq_id);
+                if let Err(e) = connection.transport.teardown() {
+                    warn!("Shutting down the connection failed ({}): {}", seq_id, e);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Store the device id, and optionally the gateway id, so that the convenience setters like
+    /// set_value() can build the payload. The gateway id defaults to the device id.
+    pub fn with_ids(mut self, dev_id: &str, gw_id: Option<&str>) -> TuyaDevice {
+        self.dev_id = Some(dev_id.to_string());
+        self.gw_id = gw_id.map(str::to_string);
+        self
+    }
+
+    /// Devices echo the sequence number of the request in the reply. With the check enabled, a
+    /// call fails with ErrorKind::SequenceMismatch if no reply has the sequence number that was
+    /// sent, e.g. a late reply to an earlier call on a persistent connection. It is off by
+    /// default since not all devices echo it correctly.
+    pub fn with_sequence_check(mut self, check: bool) -> TuyaDevice {
+        self.check_sequence = check;
+        self
+    }
+
+    /// Devices that check the clock reject commands with a t too far from their own time. With
+    /// auto timestamp enabled, set(), set_no_reply() and encode_set() replace t of the payload
+    /// with the current time right before it is encoded, however long ago it was built. It is
+    /// off by default, leaving t as given by the caller.
+    pub fn with_auto_timestamp(mut self, auto: bool) -> TuyaDevice {
+        self.auto_timestamp = auto;
+        self
+    }
+
+    /// Choose whether 3.1 messages with command are encrypted. By default every message is
+    /// encrypted, which is what most 3.1 devices accept. The 3.1 devices following the original
+    /// protocol only expect Control to be encrypted, and some of them do not reply to an
+    /// encrypted DpQuery or HeartBeat. For those, disable the encryption of these commands.
+    /// Replies are parsed whether they are encrypted or not. Versions above 3.1 always encrypt,
+    /// so this has no effect on them.
+    pub fn with_encryption(mut self, command: CommandType, encrypt: bool) -> TuyaDevice {
+        self.encryption.insert(command, encrypt);
+        self
+    }
+
+    /// Bind the UDP socket of Transport::UDP to local_addr instead of all interfaces, e.g. the
+    /// address on the IoT VLAN of a multi-homed host. If it is not the address of a local
+    /// interface, calls fail with ErrorKind::BindError. TCP connections are not bound, their
+    /// interface is picked by the routing of the OS.
+    pub fn with_local_addr(mut self, local_addr: IpAddr) -> TuyaDevice {
+        self.local_addr = Some(local_addr);
+        self
+    }
+
+    /// Record every buffer sent to and received from the device, including the session key
+    /// negotiation, see the record module.
+    pub fn with_recorder(mut self, recorder: Recorder) -> TuyaDevice {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Open a connection that is reused by all following calls, until disconnect() is called.
+    pub fn connect(&mut self) -> Result<()> {
+        let connection = self.open_connection(0, None)?;
+        if let Some(old) = self.connection_mut().replace(connection) {
+            if let Err(e) = old.transport.teardown() {
+                warn!("Closing the previous connection failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the connection opened by connect(). Following calls open a new connection each.
+    pub fn disconnect(&mut self) -> Result<()> {
+        match self.connection_mut().take() {
+            Some(connection) => connection.transport.teardown(),
+            None => Ok(()),
+        }
+    }
+
+    /// Open a connection that is used by all calls made through the returned Session, e.g. to
+    /// read the dps and write back a change without negotiating a new session key in between.
+    /// The connection is closed when the Session is dropped. If connect() has already been
+    /// called, that connection is used and left open.
+    pub fn session(&self) -> Result<Session<'_>> {
+        let mut connection = self.lock_connection();
+        let owned = connection.is_none();
+        if owned {
+            *connection = Some(self.open_connection(0, None)?);
+        }
+        Ok(Session {
+            device: self,
+            owned,
+        })
+    }
+
+    /// Open a connection that is held open to receive the Status messages the device pushes when
+    /// its state changes, e.g. for event driven automation instead of polling. The returned Watch
+    /// yields them as they arrive. A heartbeat is sent when nothing was received for
+    /// WATCH_HEARTBEAT_INTERVAL, so the device does not close the idle connection. The
+    /// connection is separate from the one opened by connect(), and many devices only accept one
+    /// connection at a time, so other calls should not be made while watching.
+    pub fn watch(&self) -> Result<Watch<'_>> {
+        let connection = self.open_connection(0, None)?;
+        connection.transport.set_timeout(WATCH_HEARTBEAT_INTERVAL)?;
+        Ok(Watch {
+            device: self,
+            connection: Some(connection),
+            pending: vec![],
+            messages: VecDeque::new(),
+        })
+    }
+
+    /// Run f with a Session, see session().
+    pub fn with_session<T>(&self, f: impl FnOnce(&Session) -> Result<T>) -> Result<T> {
+        f(&self.session()?)
+    }
+
+    /// The address of the device, with the port of its transport.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The protocol version the device was created with.
+    pub fn version(&self) -> TuyaVersion {
+        self.mp.version()
+    }
+
+    pub(crate) fn lock_connection(&self) -> MutexGuard<'_, Option<Connection>> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn connection_mut(&mut self) -> &mut Option<Connection> {
+        self.connection
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Set the dps of the device. If the device rejects the command, its error is returned as
+    /// ErrorKind::DeviceError. Some devices close or reset the connection right after accepting
+    /// a command instead of replying, that is logged as a warning and counts as success.
     pub fn set(&self, tuya_payload: Payload, seq_id: u32) -> Result<()> {
-        let mes = Message::new(tuya_payload, CommandType::Control, Some(seq_id));
-        let replies = self.send(&mes, seq_id)?;
+        let mes = self.set_message(tuya_payload, seq_id);
+        let replies = match self.send(&mes, seq_id) {
+            Err(e @ ErrorKind::ConnectionResetAfterWrite) | Err(e @ ErrorKind::EmptyReply) => {
+                warn!(
+                    "No reply from {} ({}): {}, assuming the command was applied",
+                    log_addr(&self.addr),
+                    seq_id,
+                    e
+                );
+                return Ok(());
+            }
+            result => result?,
+        };
         replies
             .iter()
             .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
-        Ok(())
+        match replies.iter().find_map(Message::device_error) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
     }
 
-    pub fn get(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<Message>> {
-        let mes = Message::new(tuya_payload, CommandType::DpQuery, Some(seq_id));
+    /// Send the dps of tuya_payload like set(), then query the device until it reports the same
+    /// values. Returns true if all sent dps match, and false if they still differ after
+    /// VERIFY_ATTEMPTS queries, VERIFY_DELAY apart. The queries use seq_id + 1 and onwards.
+    pub fn set_and_verify(&self, tuya_payload: Payload, seq_id: u32) -> Result<bool> {
+        let sent = match &tuya_payload {
+            Payload::Struct(s) => s.clone(),
+            Payload::String(_) => {
+                return Err(ErrorKind::InvalidPayloadForCommand(
+                    self.mp.set_commandtype(),
+                ))
+            }
+        };
+        self.set(tuya_payload, seq_id)?;
+        let mut query = PayloadStruct::builder(&sent.dev_id)
+            .gw_id(sent.gw_id.as_deref().unwrap_or(&sent.dev_id))
+            .timestamp_now();
+        if let Some(cid) = &sent.cid {
+            query = query.cid(cid);
+        }
+        let query = query.build();
+        let expected = sent.dps.unwrap_or_default();
+        for attempt in 1..=VERIFY_ATTEMPTS {
+            let query_seq_id = seq_id.wrapping_add(attempt);
+            let replies = self.get(query.clone(), query_seq_id)?;
+            let dps = self.reply_dps(replies, query_seq_id);
+            if expected
+                .iter()
+                .all(|(dp, value)| dps.get(dp) == Some(value))
+            {
+                return Ok(true);
+            }
+            if attempt < VERIFY_ATTEMPTS {
+                debug!(
+                    "The dps of {} do not match yet ({}), reading again in {:?}",
+                    log_addr(&self.addr),
+                    query_seq_id,
+                    VERIFY_DELAY
+                );
+                thread::sleep(VERIFY_DELAY);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Set a single dps value, e.g. `set_value("1", json!(true), 0)` to turn on a switch. The
+    /// device id must have been given with with_ids() or the builder.
+    pub fn set_value(&self, dp: &str, value: serde_json::Value, seq_id: u32) -> Result<()> {
+        let payload = self.payload_builder()?.dp(dp, value).build();
+        self.set(payload, seq_id)
+    }
+
+    /// Send the Active command, which some older 3.1 devices expect before they accept Control
+    /// commands. Other devices do not need it. The device id must have been given with
+    /// with_ids() or the builder. If the device rejects it, its error is returned as
+    /// ErrorKind::DeviceError.
+    pub fn activate(&self) -> Result<()> {
+        let seq_id = self.next_seq();
+        let payload = self.payload_builder()?.build();
+        let mes = Message::new(payload, CommandType::Active, Some(seq_id));
+        let replies = self.send(&mes, seq_id)?;
+        match replies.iter().find_map(Message::device_error) {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Trigger a scene stored on a gateway. The SceneExecute command carries no dps, the scene
+    /// is given by a sceneId field next to the usual ids:
+    /// `{"devId":"<dev id>","gwId":"<gw id>","uid":"<dev id>","t":<now>,"sceneId":"<scene id>"}`.
+    /// The device id must have been given with with_ids() or the builder.
+    pub fn execute_scene(&self, scene_id: &str, seq_id: u32) -> Result<Vec<Message>> {
+        let mut payload = self.payload_builder()?.build();
+        if let Payload::Struct(payload) = &mut payload {
+            payload
+                .extra
+                .insert("sceneId".to_string(), serde_json::json!(scene_id));
+        }
+        let mes = Message::new(payload, CommandType::SceneExecute, Some(seq_id));
         let replies = self.send(&mes, seq_id)?;
         replies
             .iter()
@@ -61,8 +602,189 @@ impl TuyaDevice {
         Ok(replies)
     }
 
+    /// Find the protocol version of the device by querying its status with each version, until
+    /// one gives a json reply. 3.1 is tried first, since its replies are not encrypted and would
+    /// otherwise be mistaken for a 3.3 reply. Then 3.3, 3.4 and 3.5 are tried. Every failed
+    /// version may take up to the timeout. The device id must have been given with with_ids() or
+    /// the builder.
+    pub fn probe_version(&self) -> Result<TuyaVersion> {
+        let payload = self.payload_builder()?.build();
+        for version in [
+            TuyaVersion::ThreeOne,
+            TuyaVersion::ThreeThree,
+            TuyaVersion::ThreeFour,
+            TuyaVersion::ThreeFive,
+        ] {
+            let mut device = TuyaDevice::create_with_mp(
+                self.mp.with_version(version.clone()),
+                self.addr.ip(),
+                self.transport,
+            );
+            device.addr = self.addr;
+            device.set_timeout(self.timeout);
+            match device.get(payload.clone(), self.next_seq()) {
+                Ok(replies) if replies.iter().any(|mes| mes.dps().is_some()) => return Ok(version),
+                Ok(_) => debug!("No valid reply with version {}", version),
+                Err(e) => debug!("Version {} failed: {}", version, e),
+            }
+        }
+        Err(ErrorKind::VersionProbeFailed)
+    }
+
+    /// A payload with the stored ids and the current time.
+    fn payload_builder(&self) -> Result<PayloadBuilder> {
+        let dev_id = self.dev_id.as_deref().ok_or(ErrorKind::MissingDevIdError)?;
+        Ok(PayloadStruct::builder(dev_id)
+            .gw_id(self.gw_id.as_deref().unwrap_or(dev_id))
+            .uid(dev_id)
+            .timestamp_now())
+    }
+
+    /// Same as set(), with the next sequence number of the device. Returns the sequence number
+    /// that was used.
+    pub fn set_auto(&self, tuya_payload: Payload) -> Result<u32> {
+        let seq_id = self.next_seq();
+        self.set(tuya_payload, seq_id)?;
+        Ok(seq_id)
+    }
+
+    /// Query the dps of the device. If the device closes or resets the connection instead of
+    /// replying, the query is sent once more on a new connection.
+    pub fn get(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<Message>> {
+        Ok(self.get_raw(tuya_payload, seq_id)?.0)
+    }
+
+    /// Same as get(), with the next sequence number of the device. Returns the sequence number
+    /// that was used together with the replies.
+    pub fn get_auto(&self, tuya_payload: Payload) -> Result<(u32, Vec<Message>)> {
+        let seq_id = self.next_seq();
+        Ok((seq_id, self.get(tuya_payload, seq_id)?))
+    }
+
+    /// Same as get(), but also returns the bytes read from the device, e.g. for debugging replies
+    /// with an unknown command or a payload that is not json.
+    pub fn get_raw(&self, tuya_payload: Payload, seq_id: u32) -> Result<(Vec<Message>, Vec<u8>)> {
+        let mes = Message::new(tuya_payload, self.mp.get_commandtype(), Some(seq_id));
+        let (replies, raw) = match self.send_raw(&mes, seq_id) {
+            Err(e @ ErrorKind::ConnectionResetAfterWrite) | Err(e @ ErrorKind::EmptyReply) => {
+                warn!(
+                    "No reply from {} ({}): {}, querying again",
+                    log_addr(&self.addr),
+                    seq_id,
+                    e
+                );
+                self.send_raw(&mes, seq_id)?
+            }
+            result => result?,
+        };
+        replies
+            .iter()
+            .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
+        Ok((replies, raw))
+    }
+
+    /// Query the current dps of the device, with the next sequence number. The device id must
+    /// have been given with with_ids() or the builder. The dps are taken from the first reply
+    /// with a json payload, an empty map is returned if it has none.
+    pub fn status(&self) -> Result<HashMap<String, serde_json::Value>> {
+        let payload = self.payload_builder()?.build();
+        let (seq_id, replies) = self.get_auto(payload)?;
+        Ok(self.reply_dps(replies, seq_id))
+    }
+
+    /// Query all dps of the device with DpQueryNew, for discovering the dps of an unknown device.
+    /// Some devices reply with more dps than to DpQuery, e.g. read-only sensor values. Not all
+    /// devices support it: if the reply has no dps, or the query fails, the status is queried
+    /// with the usual command of the version instead. For 3.4 and 3.5 that is DpQueryNew too. The
+    /// device id must have been given with with_ids() or the builder.
+    pub fn query_all(&self) -> Result<Vec<Message>> {
+        let payload = self.payload_builder()?.build();
+        let seq_id = self.next_seq();
+        let mes = Message::new(payload.clone(), CommandType::DpQueryNew, Some(seq_id));
+        match self.send(&mes, seq_id) {
+            Ok(replies) if replies.iter().any(|mes| mes.dps().is_some()) => {
+                replies
+                    .iter()
+                    .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
+                return Ok(replies);
+            }
+            Ok(_) => debug!("No dps in the DpQueryNew reply ({}), using get()", seq_id),
+            Err(e) => debug!("DpQueryNew failed ({}), using get(): {}", seq_id, e),
+        }
+        Ok(self.get_auto(payload)?.1)
+    }
+
+    /// Same as get(), but only the given dps of the first reply with a json payload are
+    /// returned. Dps that are not in the reply are left out.
+    pub fn get_filtered(
+        &self,
+        tuya_payload: Payload,
+        seq_id: u32,
+        keys: &[&str],
+    ) -> Result<HashMap<String, serde_json::Value>> {
+        let replies = self.get(tuya_payload, seq_id)?;
+        let mut dps = self.reply_dps(replies, seq_id);
+        dps.retain(|dp, _| keys.contains(&dp.as_str()));
+        Ok(dps)
+    }
+
+    /// The dps of the first reply with a json payload, empty if it has none.
+    fn reply_dps(&self, replies: Vec<Message>, seq_id: u32) -> HashMap<String, serde_json::Value> {
+        let reply = replies.into_iter().find_map(|mes| match mes.payload {
+            Payload::Struct(s) => Some(s),
+            Payload::String(_) => None,
+        });
+        match reply.and_then(|s| s.dps) {
+            Some(dps) => dps,
+            None => {
+                warn!(
+                    "The status reply from {} has no dps ({})",
+                    log_addr(&self.addr),
+                    seq_id
+                );
+                HashMap::new()
+            }
+        }
+    }
+
     pub fn refresh(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<Message>> {
         let mes = Message::new(tuya_payload, CommandType::DpRefresh, Some(seq_id));
+        // A refresh only reads the sensors, so it is safe to send again
+        let replies = match self.send(&mes, seq_id) {
+            Err(e @ ErrorKind::ConnectionResetAfterWrite) | Err(e @ ErrorKind::EmptyReply) => {
+                warn!(
+                    "No reply from {} ({}): {}, refreshing again",
+                    log_addr(&self.addr),
+                    seq_id,
+                    e
+                );
+                self.send(&mes, seq_id)?
+            }
+            result => result?,
+        };
+        replies
+            .iter()
+            .for_each(|mes| info!("Decoded response ({}):\n{}", seq_id, mes));
+        Ok(replies)
+    }
+
+    /// Read fresh values from devices that otherwise report cached ones, e.g. temperature and
+    /// energy sensors. First a DpRefresh is sent with seq_id and the device ack is awaited, which
+    /// makes the device read its sensors. Then the status is queried with seq_id + 1, and the
+    /// replies of the query are returned.
+    pub fn refresh_then_query(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<Message>> {
+        self.refresh(tuya_payload.clone(), seq_id)?;
+        self.get(tuya_payload, seq_id.wrapping_add(1))
+    }
+
+    /// Send a heartbeat to keep the connection alive, useful together with connect() since
+    /// devices close idle connections.
+    pub fn heartbeat(&self, seq_id: u32) -> Result<Vec<Message>> {
+        let mes = Message::new(
+            Payload::String("".to_string()),
+            CommandType::HeartBeat,
+            Some(seq_id),
+        );
         let replies = self.send(&mes, seq_id)?;
         replies
             .iter()
@@ -70,29 +792,1936 @@ impl TuyaDevice {
         Ok(replies)
     }
 
+    /// Check if the device is reachable by sending a heartbeat. Any well-formed reply within the
+    /// timeout counts, including an error reply or one that can not be decrypted with the local
+    /// key. Errors are logged and reported as false.
+    pub fn is_alive(&self) -> bool {
+        match self.heartbeat(self.next_seq()) {
+            Ok(replies) => !replies.is_empty(),
+            Err(ErrorKind::DeviceError { .. } | ErrorKind::DecryptionFailed) => true,
+            Err(e) => {
+                debug!("{} is not alive: {}", log_addr(&self.addr), e);
+                false
+            }
+        }
+    }
+
+    /// The message that sets the dps of tuya_payload, with a fresh t if auto timestamp is enabled.
+    fn set_message(&self, mut tuya_payload: Payload, seq_id: u32) -> Message {
+        if let Payload::Struct(payload) = &mut tuya_payload {
+            if self.auto_timestamp {
+                payload.t = now();
+            }
+        }
+        Message::new(tuya_payload, self.mp.set_commandtype(), Some(seq_id))
+    }
+
+    /// Sequence numbers for the *_auto calls start at 1 and are incremented for every call.
+    fn next_seq(&self) -> u32 {
+        self.next_seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// The bytes set() would send, without sending them, e.g. to forward them over another
+    /// channel. For 3.4 and 3.5 they are encrypted with the session key of the open connection,
+    /// if there is one, otherwise with the local key.
+    pub fn encode_set(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<u8>> {
+        let mes = self.set_message(tuya_payload, seq_id);
+        self.encode(&mes)
+    }
+
+    /// The bytes get() would send, without sending them. See encode_set().
+    pub fn encode_get(&self, tuya_payload: Payload, seq_id: u32) -> Result<Vec<u8>> {
+        let mes = Message::new(tuya_payload, self.mp.get_commandtype(), Some(seq_id));
+        self.encode(&mes)
+    }
+
+    /// The session key of the open connection of a 3.4 or 3.5 device, for troubleshooting the
+    /// handshake, e.g. against the output of tinytuya. Only built with the `session-key` feature.
+    /// None without an open connection, see connect() and session().
+    #[cfg(feature = "session-key")]
+    pub fn session_key(&self) -> Option<Vec<u8>> {
+        let connection = self.lock_connection();
+        Some(
+            connection
+                .as_ref()?
+                .session_mp
+                .as_ref()?
+                .session_key()?
+                .to_vec(),
+        )
+    }
+
+    /// Parse bytes received from the device, e.g. captured by a packet sniffer, with the key and
+    /// version of the device. Like encode_set(), the session key of the open connection is used
+    /// for 3.4 and 3.5, if there is one.
+    pub fn decode(&self, buf: &[u8]) -> Result<Vec<Message>> {
+        let connection = self.lock_connection();
+        connection
+            .as_ref()
+            .and_then(|c| c.session_mp.as_ref())
+            .unwrap_or(&self.mp)
+            .parse(buf)
+    }
+
+    fn encode(&self, mes: &Message) -> Result<Vec<u8>> {
+        let connection = self.lock_connection();
+        connection
+            .as_ref()
+            .and_then(|c| c.session_mp.as_ref())
+            .unwrap_or(&self.mp)
+            .encode(mes, self.encrypts(mes))
+    }
+
+    /// Whether mes is encrypted, see with_encryption().
+    fn encrypts(&self, mes: &Message) -> bool {
+        mes.command
+            .as_ref()
+            .and_then(|command| self.encryption.get(command))
+            .copied()
+            .unwrap_or(true)
+    }
+
     fn send(&self, mes: &Message, seq_id: u32) -> Result<Vec<Message>> {
-        let mut transport: Box<dyn TuyaTransport> = match self.transport {
-            Transport::TCP(_) => Box::new(TcpStream::connect(self.addr)?),
-            Transport::UDP(port) => Box::new(UdpSocket::bind(format!("0.0.0.0:{}", port))?),
+        Ok(self.send_raw(mes, seq_id)?.0)
+    }
+
+    fn send_raw(&self, mes: &Message, seq_id: u32) -> Result<(Vec<Message>, Vec<u8>)> {
+        let deadline = self.total_timeout.map(|total| Instant::now() + total);
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.send_once(mes, seq_id, deadline) {
+                // Only a failure that would be retried becomes a timeout, others are kept
+                Err(e)
+                    if is_retryable(&e)
+                        && deadline.is_some_and(|deadline| Instant::now() >= deadline) =>
+                {
+                    return Err(ErrorKind::Timeout)
+                }
+                Err(e) if attempt < self.retries && is_retryable(&e) => {
+                    attempt += 1;
+                    warn!(
+                        "Sending to {} failed ({}), retry {} of {} in {:?}: {}",
+                        log_addr(&self.addr),
+                        seq_id,
+                        attempt,
+                        self.retries,
+                        delay,
+                        e
+                    );
+                    thread::sleep(time_left(deadline, delay)?);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn send_once(
+        &self,
+        mes: &Message,
+        seq_id: u32,
+        deadline: Option<Instant>,
+    ) -> Result<(Vec<Message>, Vec<u8>)> {
+        let mut persistent = self.lock_connection();
+        let connection = match persistent.as_mut() {
+            Some(connection) => connection,
+            None => {
+                drop(persistent);
+                let mut connection = self.open_connection(seq_id, deadline)?;
+                let replies = self.exchange(&mut connection, mes, seq_id, deadline)?;
+                debug!("Shutting down connection ({})", seq_id);
+                // The reply is already received, a failed shutdown does not change that
+                if let Err(e) = connection.transport.teardown() {
+                    warn!("Shutting down the connection failed ({}): {}", seq_id, e);
+                }
+                return Ok(replies);
+            }
         };
-        transport.setup(self.addr)?;
-        info!("Writing message to {} ({}):\n{}", self.addr, seq_id, &mes);
-        let bts = transport.do_send(self.mp.encode(mes, true)?.as_ref())?;
+        match self.exchange(connection, mes, seq_id, deadline) {
+            Err(e @ ErrorKind::TcpError(_))
+            | Err(e @ ErrorKind::Timeout)
+            | Err(e @ ErrorKind::ConnectionRefused)
+            | Err(e @ ErrorKind::BadTcpRead)
+            | Err(e @ ErrorKind::BadUdpRead) => {
+                warn!(
+                    "Connection to {} failed, reconnecting: {}",
+                    log_addr(&self.addr),
+                    e
+                );
+                *persistent = Some(self.open_connection(seq_id, deadline)?);
+                self.exchange(persistent.as_mut().unwrap(), mes, seq_id, deadline)
+            }
+            Err(e @ ErrorKind::ConnectionResetAfterWrite) | Err(e @ ErrorKind::EmptyReply) => {
+                // The request may have been applied, so it is not sent again here
+                *persistent = Some(self.open_connection(seq_id, deadline)?);
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    pub(crate) fn open_connection(
+        &self,
+        seq_id: u32,
+        deadline: Option<Instant>,
+    ) -> Result<Connection> {
+        let transport: Box<dyn TuyaTransport + Send> = match self.transport {
+            Transport::TCP(_) => Box::new(
+                TcpStream::connect_timeout(&self.addr, time_left(deadline, self.timeout)?)
+                    .map_err(io_error)?,
+            ),
+            Transport::UDP { local, .. } => {
+                let ip = self.local_addr.unwrap_or(Ipv4Addr::UNSPECIFIED.into());
+                Box::new(bind_udp(SocketAddr::new(ip, local))?)
+            }
+            Transport::Custom => return Err(ErrorKind::CustomTransportClosed),
+        };
+        self.init_connection(transport, seq_id, deadline)
+    }
+
+    fn init_connection(
+        &self,
+        mut transport: Box<dyn TuyaTransport + Send>,
+        seq_id: u32,
+        deadline: Option<Instant>,
+    ) -> Result<Connection> {
+        transport.setup(
+            self.addr,
+            time_left(deadline, self.timeout)?,
+            &self.transport_options,
+        )?;
+        let session_mp = match self.mp.version() {
+            TuyaVersion::ThreeFour | TuyaVersion::ThreeFive => {
+                Some(self.negotiate_session_key(transport.as_mut(), seq_id, deadline)?)
+            }
+            _ => None,
+        };
+        Ok(Connection {
+            transport,
+            session_mp,
+            unread_reply: false,
+        })
+    }
+
+    fn exchange(
+        &self,
+        connection: &mut Connection,
+        mes: &Message,
+        seq_id: u32,
+        deadline: Option<Instant>,
+    ) -> Result<(Vec<Message>, Vec<u8>)> {
+        if connection.unread_reply {
+            connection.unread_reply = false;
+            self.skip_unread_reply(connection, seq_id);
+        }
+        self.write_message(connection, mes, seq_id, deadline)?;
+        let mp = connection.session_mp.as_ref().unwrap_or(&self.mp);
+        let transport = connection.transport.as_mut();
+        let buf = read_reply(transport, mp, deadline, self.timeout).map_err(|e| match e {
+            ErrorKind::TcpError(e) if e.kind() == io::ErrorKind::ConnectionReset => {
+                ErrorKind::ConnectionResetAfterWrite
+            }
+            e => e,
+        })?;
+        self.record(Direction::Received, seq_id, &buf);
+        info!("Received {} bytes ({})", buf.len(), seq_id);
+        debug!("Received response ({}):\n{}", seq_id, log_hex(&buf));
+        let replies = mp.parse(&buf)?;
+        if self.check_sequence && !replies.iter().any(|mes| mes.seq_nr == Some(seq_id)) {
+            return Err(ErrorKind::SequenceMismatch {
+                expected: seq_id,
+                got: replies[0].seq_nr.unwrap_or_default(),
+            });
+        }
+        Ok((replies, buf))
+    }
+
+    /// Read and drop the reply to a set_no_reply(), so that it is not taken as the reply to the
+    /// next request. A failed read is left to the next request to run into.
+    fn skip_unread_reply(&self, connection: &mut Connection, seq_id: u32) {
+        let mp = connection.session_mp.as_ref().unwrap_or(&self.mp);
+        let deadline = Some(Instant::now() + UNREAD_REPLY_WAIT);
+        match read_reply(
+            connection.transport.as_mut(),
+            mp,
+            deadline,
+            UNREAD_REPLY_WAIT,
+        ) {
+            Ok(buf) => debug!(
+                "Skipped the unread reply before ({}):\n{}",
+                seq_id,
+                log_hex(&buf)
+            ),
+            Err(e) => debug!("No unread reply before ({}): {}", seq_id, e),
+        }
+    }
+
+    fn write_message(
+        &self,
+        connection: &mut Connection,
+        mes: &Message,
+        seq_id: u32,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let mp = connection.session_mp.as_ref().unwrap_or(&self.mp);
+        let transport = connection.transport.as_mut();
+        info!(
+            "Writing message to {} ({}):\n{}",
+            log_addr(&self.addr),
+            seq_id,
+            &mes
+        );
+        // Also restores the timeout of a persistent connection after a call with a deadline
+        transport.set_timeout(time_left(deadline, self.timeout)?)?;
+        let encoded = mp.encode(mes, self.encrypts(mes))?;
+        self.record(Direction::Sent, seq_id, &encoded);
+        let bts = transport.do_send(&encoded)?;
         info!("Wrote {} bytes ({})", bts, seq_id);
-        let mut buf = [0; 256];
-        let bts = transport.do_read(&mut buf)?;
-        info!("Received {} bytes ({})", bts, seq_id);
+        Ok(())
+    }
+
+    /// Run the 3.4/3.5 session key negotiation on a newly set up transport. Returns a copy of the
+    /// MessageParser that encrypts with the negotiated session key.
+    fn negotiate_session_key(
+        &self,
+        transport: &mut dyn TuyaTransport,
+        seq_id: u32,
+        deadline: Option<Instant>,
+    ) -> Result<MessageParser> {
+        let mut local_nonce = [0; 16];
+        crate::cipher::rand_bytes(&mut local_nonce)?;
+        debug!(
+            "Negotiating session key with {} ({})",
+            log_addr(&self.addr),
+            seq_id
+        );
+        let start = self.mp.encode_session_key_start(&local_nonce, seq_id)?;
+        self.record(Direction::Sent, seq_id, &start);
+        transport.do_send(&start)?;
+        // Unlike the reply to a command, the reply to the negotiation is required
+        let buf = read_reply(transport, &self.mp, deadline, self.timeout).map_err(|e| match e {
+            ErrorKind::EmptyReply => transport.error(),
+            e => e,
+        })?;
+        self.record(Direction::Received, seq_id, &buf);
+        let (finish, session_key) =
+            self.mp
+                .encode_session_key_finish(&local_nonce, &buf, seq_id)?;
+        self.record(Direction::Sent, seq_id, &finish);
+        transport.do_send(&finish)?;
+        debug!(
+            "Negotiated session key ({}): {}",
+            seq_id,
+            log_hex(&session_key)
+        );
+        let mut mp = self.mp.clone();
+        mp.set_session_key(session_key);
+        Ok(mp)
+    }
+
+    fn record(&self, direction: Direction, seq_id: u32, buf: &[u8]) {
+        if let Some(recorder) = &self.recorder {
+            // A failing recording must not fail the call it records
+            if let Err(e) = recorder.record(direction, seq_id, buf) {
+                warn!(
+                    "Recording the {:?} bytes failed ({}): {}",
+                    direction, seq_id, e
+                );
+            }
+        }
+    }
+}
+
+fn is_retryable(error: &ErrorKind) -> bool {
+    matches!(
+        error,
+        ErrorKind::TcpError(_)
+            | ErrorKind::Timeout
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::BadTcpRead
+            | ErrorKind::BadUdpRead
+            | ErrorKind::ParsingIncomplete
+            | ErrorKind::CRCError
+    )
+}
+
+/// The time left until the deadline, at most timeout. ErrorKind::Timeout once the deadline has
+/// passed.
+fn time_left(deadline: Option<Instant>, timeout: Duration) -> Result<Duration> {
+    match deadline {
+        None => Ok(timeout),
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => Ok(left.min(timeout)),
+            _ => Err(ErrorKind::Timeout),
+        },
+    }
+}
+
+/// The longest time the reply to a set_no_reply() on a persistent connection is waited for,
+/// before the next request is sent.
+pub const UNREAD_REPLY_WAIT: Duration = Duration::from_millis(100);
+
+/// The largest UDP payload. A datagram is truncated if it is read into a smaller buffer.
+pub(crate) const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// Read from the transport until the received bytes parse as one or more complete messages, the
+/// device closes the connection or the read times out. A timeout is only an error if nothing at
+/// all was received, and ErrorKind::EmptyReply if the device closed the connection without
+/// sending anything. A reply split over several UDP datagrams is joined, each read takes one
+/// whole datagram. With a deadline, each read waits at most until the deadline.
+fn read_reply(
+    transport: &mut dyn TuyaTransport,
+    mp: &MessageParser,
+    deadline: Option<Instant>,
+    timeout: Duration,
+) -> Result<Vec<u8>> {
+    let mut reply = vec![];
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        if deadline.is_some() {
+            transport.set_timeout(time_left(deadline, timeout)?)?;
+        }
+        let bts = match transport.do_read(&mut buf) {
+            Ok(bts) => bts,
+            Err(ErrorKind::Timeout) if !reply.is_empty() => break,
+            Err(e) => return Err(e),
+        };
         if bts == 0 {
-            return Err(transport.error());
-        } else {
-            debug!(
-                "Received response ({}):\n{}",
-                seq_id,
-                hex::encode(&buf[..bts])
+            break;
+        }
+        reply.extend(&buf[..bts]);
+        // Stop as soon as the reply is made up of whole frames, or can never become valid
+        match mp.parse_stream(&reply) {
+            Ok((messages, used)) if !messages.is_empty() && used == reply.len() => break,
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    if reply.is_empty() {
+        return Err(ErrorKind::EmptyReply);
+    }
+    Ok(reply)
+}
+
+/// A live connection to a TuyaDevice, created with TuyaDevice::session(). All TuyaDevice methods
+/// can be called on it and are sent over the same connection. Dropping it closes the connection.
+pub struct Session<'a> {
+    device: &'a TuyaDevice,
+    owned: bool,
+}
+
+impl Deref for Session<'_> {
+    type Target = TuyaDevice;
+
+    fn deref(&self) -> &TuyaDevice {
+        self.device
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        if let Some(connection) = self.device.lock_connection().take() {
+            if let Err(e) = connection.transport.teardown() {
+                warn!("Closing the session failed: {}", e);
+            }
+        }
+    }
+}
+
+/// How long a Watch waits for data before it sends a heartbeat.
+pub const WATCH_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The Status messages pushed by a device, created with TuyaDevice::watch(). Other messages,
+/// like the replies to heartbeats, are skipped. Bytes that can not be parsed are dropped and
+/// yielded as an error. The iterator ends after the connection is closed, which is yielded as
+/// an error first. Dropping it closes the connection.
+pub struct Watch<'a> {
+    device: &'a TuyaDevice,
+    connection: Option<Connection>,
+    pending: Vec<u8>,
+    messages: VecDeque<Message>,
+}
+
+impl Watch<'_> {
+    fn heartbeat(&mut self, connection: &mut Connection) -> Result<()> {
+        let seq_id = self.device.next_seq();
+        let mes = Message::new(
+            Payload::String("".to_string()),
+            CommandType::HeartBeat,
+            Some(seq_id),
+        );
+        self.device.write_message(connection, &mes, seq_id, None)?;
+        // write_message() sets the timeout of the device
+        connection.transport.set_timeout(WATCH_HEARTBEAT_INTERVAL)
+    }
+}
+
+impl Iterator for Watch<'_> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Result<Message>> {
+        let mut connection = self.connection.take()?;
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        let result = loop {
+            if let Some(mes) = self.messages.pop_front() {
+                break Ok(mes);
+            }
+            match connection.transport.do_read(&mut buf) {
+                Ok(0) => break Err(connection.transport.error()),
+                Ok(bts) => self.pending.extend(&buf[..bts]),
+                Err(ErrorKind::Timeout) => match self.heartbeat(&mut connection) {
+                    Ok(()) => continue,
+                    Err(e) => break Err(e),
+                },
+                Err(e) => break Err(e),
+            }
+            let mp = connection.session_mp.as_ref().unwrap_or(&self.device.mp);
+            match mp.parse_stream(&self.pending) {
+                Ok((messages, used)) => {
+                    self.pending.drain(..used);
+                    self.messages.extend(
+                        messages
+                            .into_iter()
+                            .filter(|mes| mes.command == Some(CommandType::Status)),
+                    );
+                }
+                Err(e) => {
+                    self.pending.clear();
+                    // The connection is still usable, only the received bytes are dropped
+                    self.connection = Some(connection);
+                    return Some(Err(e));
+                }
+            }
+        };
+        match result {
+            Ok(mes) => {
+                info!("Received status ({:?}):\n{}", mes.seq_nr, mes);
+                self.connection = Some(connection);
+                Some(Ok(mes))
+            }
+            Err(e) => {
+                warn!("Watching {} stopped: {}", log_addr(&self.device.addr), e);
+                if let Err(e) = connection.transport.teardown() {
+                    debug!("Closing the watched connection failed: {}", e);
+                }
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl Drop for Watch<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            if let Err(e) = connection.transport.teardown() {
+                warn!("Closing the watched connection failed: {}", e);
+            }
+        }
+    }
+}
+
+/// Call set() on every device with its payload, each on its own thread, e.g. to turn off all
+/// lights at once. The results are returned in the order of the devices.
+pub fn set_all(devices: &[(&TuyaDevice, Payload)], seq_id: u32) -> Vec<Result<()>> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = devices
+            .iter()
+            .map(|(device, payload)| scope.spawn(move || device.set(payload.clone(), seq_id)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    })
+}
+
+/// A device found by discover(), as announced in its UDP broadcast.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct DiscoveredDevice {
+    #[serde(rename = "gwId")]
+    pub gw_id: String,
+    pub ip: IpAddr,
+    #[serde(rename = "productKey", default)]
+    pub product_key: String,
+    pub version: String,
+}
+
+/// Listen for the broadcasts Tuya devices send on UDP port 6666 (3.1) and 6667 (3.3 and later)
+/// for the given duration. The 3.3 broadcasts are encrypted with the well known UDP key, which
+/// is the key a MessageParser uses when created without a key. Every device is returned once,
+/// even if it broadcasted several times.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredDevice>> {
+    discover_with_key(timeout, UDP_KEY)
+}
+
+/// Same as discover(), for devices that encrypt their broadcasts with another discovery key.
+pub fn discover_with_key(timeout: Duration, discovery_key: &str) -> Result<Vec<DiscoveredDevice>> {
+    discover_broadcasts(timeout, discovery_key, None)
+}
+
+/// Same as discover_with_key(), but only devices that are reached through the interface with
+/// address local_addr are returned, e.g. the IoT VLAN of a multi-homed host. Fails with
+/// ErrorKind::BindError if local_addr is not the address of a local interface.
+pub fn discover_on(
+    timeout: Duration,
+    discovery_key: &str,
+    local_addr: IpAddr,
+) -> Result<Vec<DiscoveredDevice>> {
+    // Fail early rather than listening for devices that can not be reached
+    bind_udp(SocketAddr::new(local_addr, 0))?;
+    discover_broadcasts(timeout, discovery_key, Some(local_addr))
+}
+
+fn discover_broadcasts(
+    timeout: Duration,
+    discovery_key: &str,
+    local_addr: Option<IpAddr>,
+) -> Result<Vec<DiscoveredDevice>> {
+    let mp = MessageParser::create_with_discovery_key("3.3", discovery_key)?;
+    // The broadcasts are received on all interfaces, since a socket bound to the address of an
+    // interface does not receive broadcasts on Linux. Devices on other interfaces are dropped
+    // afterwards instead.
+    let sockets = [
+        UdpSocket::bind("0.0.0.0:6666")?,
+        UdpSocket::bind("0.0.0.0:6667")?,
+    ];
+    let deadline = Instant::now() + timeout;
+    let mut devices: Vec<DiscoveredDevice> = vec![];
+    let mut buf = [0; 1024];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        for socket in &sockets {
+            // A zero read timeout is not allowed
+            let slice = remaining.clamp(Duration::from_millis(1), Duration::from_millis(100));
+            socket.set_read_timeout(Some(slice))?;
+            match socket.recv(&mut buf) {
+                Ok(bts) => {
+                    for device in parse_broadcast(&mp, &buf[..bts]) {
+                        if !devices.iter().any(|d| d.gw_id == device.gw_id)
+                            && local_addr.is_none_or(|local| routed_via(local, device.ip))
+                        {
+                            info!("Discovered device {} at {}", device.gw_id, device.ip);
+                            devices.push(device);
+                        }
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(devices)
+}
+
+fn bind_udp(addr: SocketAddr) -> Result<UdpSocket> {
+    UdpSocket::bind(addr).map_err(|e| ErrorKind::BindError(addr, e))
+}
+
+/// Whether the OS sends packets to remote from the interface with address local. Connecting a
+/// UDP socket sends nothing, it only picks the route.
+fn routed_via(local: IpAddr, remote: IpAddr) -> bool {
+    let unspecified: IpAddr = match remote {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = match UdpSocket::bind(SocketAddr::new(unspecified, 0)) {
+        Ok(socket) => socket,
+        Err(_) => return false,
+    };
+    socket
+        .connect(SocketAddr::new(remote, 6666))
+        .and_then(|()| socket.local_addr())
+        .is_ok_and(|addr| addr.ip() == local)
+}
+
+fn parse_broadcast(mp: &MessageParser, buf: &[u8]) -> Vec<DiscoveredDevice> {
+    match mp.parse(buf) {
+        // The broadcast has no devId, so it is not parsed in to a PayloadStruct
+        Ok(messages) => messages
+            .into_iter()
+            .filter_map(|mes| match mes.payload {
+                Payload::String(s) => serde_json::from_str(&s).ok(),
+                Payload::Struct(_) => None,
+            })
+            .collect(),
+        Err(e) => {
+            debug!("Ignoring invalid broadcast: {}", e);
+            vec![]
+        }
+    }
+}
+
+#[cfg(all(test, feature = "crypto"))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+
+    const BROADCAST: &str = r#"{"ip":"192.168.0.123","gwId":"002004265ccf7fb1b659","active":2,"ability":0,"mode":0,"encrypt":true,"productKey":"keyjup78v54myhan","version":"3.3"}"#;
+
+    fn expected_device() -> DiscoveredDevice {
+        DiscoveredDevice {
+            gw_id: "002004265ccf7fb1b659".to_string(),
+            ip: IpAddr::from_str("192.168.0.123").unwrap(),
+            product_key: "keyjup78v54myhan".to_string(),
+            version: "3.3".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_encrypted_broadcast() {
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let mes = Message::new(
+            Payload::String(BROADCAST.to_string()),
+            CommandType::UdpNew,
+            None,
+        );
+        let packet = mp.encode(&mes, true).unwrap();
+        assert_eq!(parse_broadcast(&mp, &packet), vec![expected_device()]);
+    }
+
+    #[test]
+    fn parse_unencrypted_broadcast() {
+        let mp = MessageParser::create("3.3", None).unwrap();
+        let mes = Message::new(
+            Payload::String(BROADCAST.to_string()),
+            CommandType::Udp,
+            None,
+        );
+        let packet = MessageParser::create("3.1", None)
+            .unwrap()
+            .encode(&mes, false)
+            .unwrap();
+        assert_eq!(parse_broadcast(&mp, &packet), vec![expected_device()]);
+    }
+
+    #[test]
+    fn parse_invalid_broadcast() {
+        let mp = MessageParser::create("3.3", None).unwrap();
+        assert!(parse_broadcast(&mp, b"not a tuya frame").is_empty());
+    }
+
+    #[test]
+    fn get_reads_reply_longer_than_one_read_in_chunks() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let dps: HashMap<String, serde_json::Value> = (1..40)
+            .map(|dp| (dp.to_string(), json!(dp * 100)))
+            .collect();
+        let reply = Message {
+            payload: Payload::Struct(PayloadStruct {
+                dev_id: "002004265ccf7fb1b659".to_string(),
+                gw_id: None,
+                cid: None,
+                uid: None,
+                t: None,
+                s: None,
+                dp_id: None,
+                dps: Some(dps),
+                extra: HashMap::new(),
+            }),
+            command: Some(CommandType::DpQuery),
+            raw_command: None,
+            seq_nr: Some(1),
+            ret_code: None,
+        };
+        let encoded = mp.encode(&reply, true).unwrap();
+        assert!(encoded.len() > 256);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            // Dribble two concatenated frames in small pieces. The client may stop reading
+            // after the first complete frame, so write errors are ignored.
+            let frames = [encoded.clone(), encoded].concat();
+            for chunk in frames.chunks(100) {
+                if stream.write_all(chunk).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        let device = TuyaDevice::create_with_mp(
+            mp,
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        let replies = device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        server.join().unwrap();
+        assert_eq!(replies[0], reply);
+    }
+
+    #[test]
+    fn get_times_out_after_configured_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        device.set_timeout(Duration::from_millis(100));
+
+        // The listener accepts the connection but never replies
+        let start = Instant::now();
+        assert!(matches!(
+            device.get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1
+            ),
+            Err(ErrorKind::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    /// Start a fake device on localhost. It accepts one connection per entry in
+    /// requests_per_connection, and answers that many requests with the reply before closing.
+    fn spawn_device(
+        reply: Vec<u8>,
+        requests_per_connection: Vec<usize>,
+    ) -> (TuyaDevice, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for requests in requests_per_connection {
+                let (mut stream, _) = listener.accept().unwrap();
+                for _ in 0..requests {
+                    let mut request = [0; 1024];
+                    let _ = stream.read(&mut request).unwrap();
+                    stream.write_all(&reply).unwrap();
+                }
+            }
+        });
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        (device, server)
+    }
+
+    fn status_reply() -> (Message, Vec<u8>) {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message {
+            payload: PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            command: Some(CommandType::DpQuery),
+            raw_command: None,
+            seq_nr: Some(1),
+            ret_code: None,
+        };
+        let encoded = mp.encode(&reply, true).unwrap();
+        (reply, encoded)
+    }
+
+    #[test]
+    fn get_raw_returns_received_bytes() {
+        let (reply, encoded) = status_reply();
+        let (device, server) = spawn_device(encoded.clone(), vec![1]);
+        let (replies, raw) = device
+            .get_raw(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert_eq!(replies, vec![reply]);
+        assert_eq!(raw, encoded);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn recorder_records_sent_and_received_bytes() {
+        let (_, encoded) = status_reply();
+        let (device, server) = spawn_device(encoded.clone(), vec![1]);
+        let path = std::env::temp_dir().join(format!("tuyapi-device-{}.rec", std::process::id()));
+        let device = device.with_recorder(Recorder::create(&path).unwrap());
+        device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                4,
+            )
+            .unwrap();
+        server.join().unwrap();
+
+        let records = crate::record::read_records(std::fs::File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].seq_nr, 4);
+        assert_eq!(records[0].parse(&device.mp).unwrap()[0].seq_nr, Some(4));
+        assert_eq!(records[1].direction, Direction::Received);
+        assert_eq!(records[1].data, encoded);
+    }
+
+    #[test]
+    fn get_over_udp_from_os_picked_port() {
+        let (reply, encoded) = status_reply();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut request = [0; 1024];
+            let (_, from) = socket.recv_from(&mut request).unwrap();
+            assert_ne!(from.port(), port);
+            socket.send_to(&encoded, from).unwrap();
+        });
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::UDP {
+                local: 0,
+                remote: port,
+            },
+        );
+        let replies = device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert_eq!(replies[0], reply);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_over_udp_from_local_addr() {
+        let (reply, encoded) = status_reply();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut request = [0; 1024];
+            let (_, from) = socket.recv_from(&mut request).unwrap();
+            socket.send_to(&encoded, from).unwrap();
+        });
+        let transport = Transport::UDP {
+            local: 0,
+            remote: port,
+        };
+        let query = Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659");
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            transport,
+        )
+        .with_local_addr(IpAddr::from_str("127.0.0.1").unwrap());
+        assert_eq!(device.get(query.clone(), 1).unwrap()[0], reply);
+        server.join().unwrap();
+
+        // An address from TEST-NET-1 is not assigned to any interface
+        let device = device.with_local_addr(IpAddr::from_str("192.0.2.1").unwrap());
+        assert!(matches!(
+            device.get(query, 2),
+            Err(ErrorKind::BindError(addr, _)) if addr.ip() == IpAddr::from_str("192.0.2.1").unwrap()
+        ));
+        assert!(matches!(
+            discover_on(
+                Duration::from_millis(10),
+                UDP_KEY,
+                IpAddr::from_str("192.0.2.1").unwrap()
+            ),
+            Err(ErrorKind::BindError(..))
+        ));
+    }
+
+    #[test]
+    fn routed_via_interface() {
+        let localhost = IpAddr::from_str("127.0.0.1").unwrap();
+        assert!(routed_via(localhost, localhost));
+        assert!(!routed_via(
+            IpAddr::from_str("192.0.2.1").unwrap(),
+            localhost
+        ));
+    }
+
+    #[test]
+    fn get_over_udp_joins_reply_split_over_datagrams() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let mut builder = PayloadStruct::builder("002004265ccf7fb1b659");
+        for dp in 1..40 {
+            builder = builder.dp(&dp.to_string(), dp * 100);
+        }
+        let reply = Message::new(builder.build(), CommandType::DpQuery, Some(1));
+        let encoded = mp.encode(&reply, true).unwrap();
+        assert!(encoded.len() > 256);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut request = [0; 1024];
+            let (_, from) = socket.recv_from(&mut request).unwrap();
+            let (first, second) = encoded.split_at(20);
+            socket.send_to(first, from).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            socket.send_to(second, from).unwrap();
+        });
+        let mut device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::UDP {
+                local: 0,
+                remote: port,
+            },
+        );
+        device.set_timeout(Duration::from_secs(5));
+
+        // The complete reply is returned without waiting for the timeout
+        let start = Instant::now();
+        let replies = device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(replies, vec![reply]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn encode_and_decode_use_command_of_version() {
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .build();
+        for (version, set, get) in [
+            ("3.3", CommandType::Control, CommandType::DpQuery),
+            ("3.4", CommandType::ControlNew, CommandType::DpQueryNew),
+        ] {
+            let mp = MessageParser::create(version, Some("bbe88b3f4106d354")).unwrap();
+            let device = TuyaDevice::create_with_mp(
+                mp.clone(),
+                IpAddr::from_str("127.0.0.1").unwrap(),
+                Transport::TCP(6668),
             );
+            let encoded = device.encode_set(payload.clone(), 1).unwrap();
+            assert_eq!(
+                mp.parse(&encoded).unwrap(),
+                vec![Message::new(payload.clone(), set, Some(1))]
+            );
+            let encoded = device.encode_get(payload.clone(), 2).unwrap();
+            assert_eq!(device.decode(&encoded).unwrap()[0].command, Some(get));
         }
-        debug!("Shutting down connection ({})", seq_id);
-        transport.teardown()?;
-        self.mp.parse(&buf[..bts])
+    }
+
+    #[test]
+    fn addr_and_version_of_device() {
+        let device = TuyaDevice::builder()
+            .version("3.4")
+            .key("bbe88b3f4106d354")
+            .ip(IpAddr::from_str("192.168.0.123").unwrap())
+            .transport(Transport::TCP(6669))
+            .build()
+            .unwrap();
+        assert_eq!(
+            device.addr(),
+            SocketAddr::from_str("192.168.0.123:6669").unwrap()
+        );
+        assert_eq!(device.version(), TuyaVersion::ThreeFour);
+    }
+
+    #[test]
+    fn encryption_of_three_one_per_command() {
+        let payload = Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659");
+        let mp = MessageParser::create("3.1", Some("bbe88b3f4106d354")).unwrap();
+        let device = TuyaDevice::create_with_mp(
+            mp.clone(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(6668),
+        );
+        let plain = mp
+            .encode(
+                &Message::new(payload.clone(), CommandType::DpQuery, Some(1)),
+                false,
+            )
+            .unwrap();
+        assert_ne!(device.encode_get(payload.clone(), 1).unwrap(), plain);
+
+        let device = device.with_encryption(CommandType::DpQuery, false);
+        assert_eq!(device.encode_get(payload.clone(), 1).unwrap(), plain);
+        // Control is still encrypted
+        let control = Message::new(payload.clone(), CommandType::Control, Some(2));
+        assert_ne!(
+            device.encode_set(payload, 2).unwrap(),
+            mp.encode(&control, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn auto_timestamp_replaces_stale_t() {
+        let mut payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .build();
+        if let Payload::Struct(s) = &mut payload {
+            s.t = Some(1_600_000_000);
+        }
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let device = TuyaDevice::create_with_mp(
+            mp.clone(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(6668),
+        );
+        let t_of = |encoded: &[u8]| match &mp.parse(encoded).unwrap()[0].payload {
+            Payload::Struct(s) => s.t,
+            other => panic!("unexpected payload {:?}", other),
+        };
+
+        let encoded = device.encode_set(payload.clone(), 1).unwrap();
+        assert_eq!(t_of(&encoded), Some(1_600_000_000));
+
+        let device = device.with_auto_timestamp(true);
+        let encoded = device.encode_set(payload, 2).unwrap();
+        assert!(t_of(&encoded).unwrap() >= now().unwrap());
+    }
+
+    #[test]
+    fn status_returns_dps_of_reply() {
+        let (reply, encoded) = status_reply();
+        let (device, server) = spawn_device(encoded, vec![1]);
+        assert!(matches!(device.status(), Err(ErrorKind::MissingDevIdError)));
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        assert_eq!(&device.status().unwrap(), reply.dps().unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_filtered_returns_requested_dps() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .dp("2", 100)
+                .dp("3", "white")
+                .build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let (device, server) = spawn_device(mp.encode(&reply, true).unwrap(), vec![1]);
+        let dps = device
+            .get_filtered(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+                &["1", "3", "4"],
+            )
+            .unwrap();
+        assert_eq!(
+            dps,
+            HashMap::from([
+                ("1".to_string(), json!(true)),
+                ("3".to_string(), json!("white"))
+            ])
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn set_all_returns_results_in_order() {
+        let reply = Message::new(Payload::from(""), CommandType::Control, Some(1));
+        let reply = MessageParser::create("3.3", Some("bbe88b3f4106d354"))
+            .unwrap()
+            .encode(&reply, true)
+            .unwrap();
+        let (first, first_server) = spawn_device(reply.clone(), vec![1]);
+        let (second, second_server) = spawn_device(reply, vec![1]);
+        // Nothing listens on the port of the closed device
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let closed = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", false)
+            .build();
+
+        let results = set_all(
+            &[
+                (&first, payload.clone()),
+                (&closed, payload.clone()),
+                (&second, payload),
+            ],
+            1,
+        );
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+        first_server.join().unwrap();
+        second_server.join().unwrap();
+    }
+
+    #[test]
+    fn status_without_dps_is_empty() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659").build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        let (device, server) = spawn_device(mp.encode(&reply, true).unwrap(), vec![1]);
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        assert!(device.status().unwrap().is_empty());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn total_timeout_limits_call_with_retries() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut device = TuyaDevice::builder_with_key(
+            "3.3",
+            Some("bbe88b3f4106d354"),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+        )
+        .transport(Transport::TCP(port))
+        .timeout(Duration::from_secs(1))
+        .total_timeout(Duration::from_millis(300))
+        .retries(3, Duration::from_millis(50))
+        .build()
+        .unwrap();
+
+        // The listener accepts the connections but never replies
+        let start = Instant::now();
+        assert!(matches!(
+            device.get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1
+            ),
+            Err(ErrorKind::Timeout)
+        ));
+        assert!(start.elapsed() < Duration::from_millis(900));
+
+        // A read timeout is reported the same way
+        device.set_total_timeout(None);
+        device.set_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            device.get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1
+            ),
+            Err(ErrorKind::Timeout)
+        ));
+        drop(listener);
+    }
+
+    #[test]
+    fn set_no_reply_returns_without_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            stream.read_to_end(&mut request).unwrap();
+            request
+        });
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let device = TuyaDevice::create_with_mp(
+            mp.clone(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        let start = Instant::now();
+        device
+            .set_no_reply(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                3,
+            )
+            .unwrap();
+        assert!(start.elapsed() < device.timeout);
+        let sent = mp.parse(&server.join().unwrap()).unwrap();
+        assert_eq!(sent[0].command, Some(CommandType::Control));
+        assert_eq!(sent[0].seq_nr, Some(3));
+    }
+
+    #[test]
+    fn set_no_reply_reply_is_not_taken_as_next_reply() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        // Devices reply to Control with the dps that changed
+        let ack = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", false)
+                .build(),
+            CommandType::Control,
+            Some(3),
+        );
+        let replies = vec![mp.encode(&ack, true).unwrap(), status_reply().1];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for reply in replies {
+                let _ = stream.read(&mut [0; 1024]).unwrap();
+                stream.write_all(&reply).unwrap();
+            }
+        });
+        let mut device = TuyaDevice::create_with_mp(
+            mp,
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        )
+        .with_ids("002004265ccf7fb1b659", None);
+        device.connect().unwrap();
+
+        device
+            .set_no_reply(
+                PayloadStruct::builder("002004265ccf7fb1b659")
+                    .dp("1", false)
+                    .build(),
+                3,
+            )
+            .unwrap();
+        assert_eq!(
+            device.status().unwrap(),
+            HashMap::from([("1".to_string(), json!(true))])
+        );
+        server.join().unwrap();
+    }
+
+    /// Accepts one connection per entry of replies. The connection is reset after the request is
+    /// received if the entry is None, otherwise the reply is sent.
+    fn spawn_resetting_device(
+        replies: Vec<Option<Vec<u8>>>,
+    ) -> (TuyaDevice, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                thread::sleep(Duration::from_millis(50));
+                match reply {
+                    // Closing with unread data makes the OS reset the connection
+                    None => stream.read_exact(&mut [0; 1]).unwrap(),
+                    Some(reply) => {
+                        let _ = stream.read(&mut [0; 1024]).unwrap();
+                        stream.write_all(&reply).unwrap();
+                    }
+                }
+            }
+        });
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        (device, server)
+    }
+
+    #[test]
+    fn set_and_verify_reads_until_dps_match() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let ack = mp
+            .encode(
+                &Message::new(
+                    Payload::String("".to_string()),
+                    CommandType::Control,
+                    Some(1),
+                ),
+                true,
+            )
+            .unwrap();
+        let status = |on: bool| {
+            let reply = Message::new(
+                PayloadStruct::builder("002004265ccf7fb1b659")
+                    .dp("1", on)
+                    .build(),
+                CommandType::DpQuery,
+                Some(2),
+            );
+            mp.encode(&reply, true).unwrap()
+        };
+        let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .build();
+
+        // The device reports the old value once before the new one
+        let (device, server) = spawn_resetting_device(vec![
+            Some(ack.clone()),
+            Some(status(false)),
+            Some(status(true)),
+        ]);
+        assert!(device.set_and_verify(payload.clone(), 1).unwrap());
+        server.join().unwrap();
+
+        let replies = [vec![Some(ack)], vec![Some(status(false)); 3]].concat();
+        let (device, server) = spawn_resetting_device(replies);
+        assert!(!device.set_and_verify(payload, 1).unwrap());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn query_all_falls_back_to_get() {
+        let (reply, encoded) = status_reply();
+        let (device, server) = spawn_resetting_device(vec![Some(encoded.clone())]);
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        assert_eq!(device.query_all().unwrap(), vec![reply]);
+        server.join().unwrap();
+
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let error = Message {
+            payload: Payload::String("data format error".to_string()),
+            command: Some(CommandType::DpQueryNew),
+            raw_command: None,
+            seq_nr: Some(1),
+            ret_code: Some(1),
+        };
+        let (device, server) =
+            spawn_resetting_device(vec![Some(mp.encode(&error, true).unwrap()), Some(encoded)]);
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        assert_eq!(device.query_all().unwrap(), vec![status_reply().0]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn set_succeeds_when_device_resets_connection() {
+        let (device, server) = spawn_resetting_device(vec![None]);
+        device
+            .set(
+                PayloadStruct::builder("002004265ccf7fb1b659")
+                    .dp("1", true)
+                    .build(),
+                1,
+            )
+            .unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn get_queries_again_when_device_resets_connection() {
+        let (reply, encoded) = status_reply();
+        let (device, server) = spawn_resetting_device(vec![None, Some(encoded)]);
+        let replies = device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert_eq!(replies, vec![reply]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn watch_yields_pushed_status_messages() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let status = |on: bool| Message {
+            payload: PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", on)
+                .build(),
+            command: Some(CommandType::Status),
+            raw_command: None,
+            seq_nr: Some(0),
+            ret_code: None,
+        };
+        let heartbeat = Message {
+            payload: Payload::String("".to_string()),
+            command: Some(CommandType::HeartBeat),
+            raw_command: None,
+            seq_nr: Some(0),
+            ret_code: None,
+        };
+        let mut pushed = mp.encode(&status(true), true).unwrap();
+        pushed.extend(mp.encode(&heartbeat, true).unwrap());
+        pushed.extend(mp.encode(&status(false), true).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Push in two parts, split inside the second status message
+            let (first, second) = pushed.split_at(pushed.len() - 10);
+            stream.write_all(first).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(second).unwrap();
+        });
+        let device = TuyaDevice::create_with_mp(
+            mp,
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+
+        let mut watch = device.watch().unwrap();
+        assert_eq!(watch.next().unwrap().unwrap(), status(true));
+        assert_eq!(watch.next().unwrap().unwrap(), status(false));
+        server.join().unwrap();
+        assert!(matches!(watch.next(), Some(Err(ErrorKind::BadTcpRead))));
+        assert!(watch.next().is_none());
+    }
+
+    #[test]
+    fn set_returns_device_error() {
+        // A "data format error" reply, encrypted with the UDP key
+        let reply = hex::decode("000055aa00000000000000070000003b00000001332e33d504910232d355a59ed1f6ed1f4a816a1e8e30ed09987c020ae45d72c70592bb233c79c43a5b9ae49b6ead38725deb520000aa55").unwrap();
+        let (mut device, server) = spawn_device(reply, vec![1]);
+        device.mp = MessageParser::create("3.3", None).unwrap();
+        let result = device.set(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            1,
+        );
+        assert!(matches!(
+            result,
+            Err(ErrorKind::DeviceError { code: 1, message }) if message == "data format error"
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn sequence_check_rejects_reply_to_other_request() {
+        let (reply, encoded) = status_reply();
+        let (device, server) = spawn_device(encoded, vec![1, 1]);
+        let device = device.with_sequence_check(true);
+        assert!(matches!(
+            device.get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                2
+            ),
+            Err(ErrorKind::SequenceMismatch {
+                expected: 2,
+                got: 1
+            })
+        ));
+        let replies = device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert_eq!(replies[0], reply);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn auto_sequence_numbers_increment() {
+        let (reply, encoded) = status_reply();
+        let (device, server) = spawn_device(encoded, vec![1, 1]);
+        let (seq_id, replies) = device
+            .get_auto(Payload::query(
+                "002004265ccf7fb1b659",
+                "002004265ccf7fb1b659",
+            ))
+            .unwrap();
+        assert_eq!(seq_id, 1);
+        assert_eq!(replies[0], reply);
+        assert_eq!(
+            device
+                .set_auto(Payload::query(
+                    "002004265ccf7fb1b659",
+                    "002004265ccf7fb1b659"
+                ))
+                .unwrap(),
+            2
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn activate_sends_active_command() {
+        let mp = MessageParser::create("3.1", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message::new(
+            Payload::String("".to_string()),
+            CommandType::Active,
+            Some(1),
+        );
+        let (mut device, server) = spawn_device(mp.encode(&reply, false).unwrap(), vec![1]);
+        device.mp = mp;
+        assert!(matches!(
+            device.activate(),
+            Err(ErrorKind::MissingDevIdError)
+        ));
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        device.activate().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refresh_then_query_returns_query_replies() {
+        let (reply, encoded) = status_reply();
+        // One connection for the refresh and one for the query
+        let (device, server) = spawn_device(encoded, vec![1, 1]);
+        let replies = device
+            .refresh_then_query(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert_eq!(replies, vec![reply]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn probe_version_finds_version_with_json_reply() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message::new(
+            PayloadStruct::builder("002004265ccf7fb1b659")
+                .dp("1", true)
+                .build(),
+            CommandType::DpQuery,
+            Some(1),
+        );
+        // 3.1 is tried on the first connection, 3.3 on the second
+        let (device, server) = spawn_device(mp.encode(&reply, true).unwrap(), vec![1, 1]);
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        assert_eq!(device.probe_version().unwrap(), TuyaVersion::ThreeThree);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn set_value_uses_stored_ids() {
+        let (_, encoded) = status_reply();
+        let (device, server) = spawn_device(encoded, vec![1]);
+        assert!(matches!(
+            device.set_value("1", json!(true), 1),
+            Err(ErrorKind::MissingDevIdError)
+        ));
+        let device = device.with_ids("002004265ccf7fb1b659", None);
+        device.set_value("1", json!(true), 1).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn persistent_connection_is_reused() {
+        let (reply, encoded) = status_reply();
+        // Only a single connection is accepted
+        let (mut device, server) = spawn_device(encoded, vec![2]);
+        device.connect().unwrap();
+        for _ in 0..2 {
+            let replies = device
+                .get(
+                    Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                    1,
+                )
+                .unwrap();
+            assert_eq!(replies[0], reply);
+        }
+        device.disconnect().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn session_reuses_connection_and_closes_on_drop() {
+        let (reply, encoded) = status_reply();
+        // One connection for the session, one for the call after it
+        let (device, server) = spawn_device(encoded, vec![2, 1]);
+        device
+            .with_session(|session| {
+                let replies = session.get(
+                    Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                    1,
+                )?;
+                assert_eq!(replies[0], reply);
+                session.set(
+                    Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                    2,
+                )
+            })
+            .unwrap();
+        assert!(device.lock_connection().is_none());
+        device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                3,
+            )
+            .unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn persistent_connection_reconnects_when_closed() {
+        let (reply, encoded) = status_reply();
+        // The first connection is closed by the device after one request
+        let (mut device, server) = spawn_device(encoded, vec![1, 1]);
+        device.connect().unwrap();
+        for _ in 0..2 {
+            let replies = device
+                .get(
+                    Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                    1,
+                )
+                .unwrap();
+            assert_eq!(replies[0], reply);
+        }
+        device.disconnect().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn heartbeat_over_persistent_connection() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message {
+            payload: Payload::String("".to_string()),
+            command: Some(CommandType::HeartBeat),
+            raw_command: None,
+            seq_nr: Some(2),
+            ret_code: None,
+        };
+        let (mut device, server) = spawn_device(mp.encode(&reply, true).unwrap(), vec![2]);
+        device.connect().unwrap();
+        assert_eq!(device.heartbeat(2).unwrap(), vec![reply]);
+        assert!(device.heartbeat(3).is_ok());
+        device.disconnect().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn is_alive_on_any_reply() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message {
+            payload: Payload::String("data format error".to_string()),
+            command: Some(CommandType::HeartBeat),
+            raw_command: None,
+            seq_nr: Some(1),
+            ret_code: Some(1),
+        };
+        let (device, server) = spawn_device(mp.encode(&reply, true).unwrap(), vec![1]);
+        assert!(device.is_alive());
+        server.join().unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let device = TuyaDevice::create_with_mp(
+            mp,
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        assert!(!device.is_alive());
+    }
+
+    #[test]
+    fn execute_scene_sends_scene_id() {
+        let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+        let reply = Message::new(
+            Payload::String("".to_string()),
+            CommandType::SceneExecute,
+            Some(4),
+        );
+        let encoded = mp.encode(&reply, true).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server_mp = mp.clone();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let n = stream.read(&mut request).unwrap();
+            stream.write_all(&encoded).unwrap();
+            server_mp.parse(&request[..n]).unwrap().remove(0)
+        });
+        let device = TuyaDevice::create_with_mp(
+            mp,
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        )
+        .with_ids("002004265ccf7fb1b659", None);
+
+        assert_eq!(device.execute_scene("scene1", 4).unwrap(), vec![reply]);
+        let request = server.join().unwrap();
+        assert_eq!(request.command, Some(CommandType::SceneExecute));
+        match request.payload {
+            Payload::Struct(payload) => {
+                assert_eq!(payload.dev_id, "002004265ccf7fb1b659");
+                assert_eq!(payload.extra["sceneId"], json!("scene1"));
+                assert!(payload.dps.is_none());
+            }
+            other => panic!("unexpected payload {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_reply_is_success_for_set_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            // set() sends once, get() queries again
+            for _ in 0..3 {
+                // Read the whole request, so that closing sends no reset
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0; 1024]).unwrap();
+            }
+        });
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        device
+            .set(
+                PayloadStruct::builder("002004265ccf7fb1b659")
+                    .dp("1", true)
+                    .build(),
+                1,
+            )
+            .unwrap();
+        let result = device.get(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            2,
+        );
+        assert!(matches!(result, Err(ErrorKind::EmptyReply)));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn retry_when_device_closes_connection() {
+        let (reply, encoded) = status_reply();
+        // The first connection is closed without a reply
+        let (device, server) = spawn_device(encoded, vec![0, 1]);
+        let device = device.with_retries(2, Duration::from_millis(10));
+        let replies = device
+            .get(
+                Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+                1,
+            )
+            .unwrap();
+        assert_eq!(replies[0], reply);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn set_is_not_resent_when_device_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut requests = 0;
+            listener.set_nonblocking(true).unwrap();
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(500) {
+                match listener.accept() {
+                    Ok((mut stream, _)) => {
+                        stream.set_nonblocking(false).unwrap();
+                        // Read the whole request, so that closing sends no reset
+                        if stream.read(&mut [0; 1024]).unwrap() > 0 {
+                            requests += 1;
+                        }
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            }
+            requests
+        });
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        )
+        .with_retries(2, Duration::from_millis(10));
+        device
+            .set(
+                PayloadStruct::builder("002004265ccf7fb1b659")
+                    .dp("1", true)
+                    .build(),
+                1,
+            )
+            .unwrap();
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn connecting_to_closed_port_is_refused() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let device = TuyaDevice::create_with_mp(
+            MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap(),
+            IpAddr::from_str("127.0.0.1").unwrap(),
+            Transport::TCP(port),
+        );
+        let result = device.get(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            1,
+        );
+        assert!(matches!(result, Err(ErrorKind::ConnectionRefused)));
+    }
+
+    #[test]
+    fn no_retry_on_non_transient_errors() {
+        assert!(is_retryable(&ErrorKind::CRCError));
+        assert!(!is_retryable(&ErrorKind::KeyLength(8)));
+        assert!(!is_retryable(&ErrorKind::VersionError(
+            "3".to_string(),
+            "9".to_string()
+        )));
+    }
+
+    #[test]
+    fn builder_requires_version_and_ip() {
+        let ip = IpAddr::from_str("192.168.0.123").unwrap();
+        assert!(matches!(
+            TuyaDevice::builder().version("3.3").build(),
+            Err(ErrorKind::MissingAddressError)
+        ));
+        assert!(matches!(
+            TuyaDevice::builder().ip(ip).build(),
+            Err(ErrorKind::VersionError(_, _))
+        ));
+        assert!(matches!(
+            TuyaDevice::builder()
+                .version("3.3")
+                .key("tooshort")
+                .ip(ip)
+                .build(),
+            Err(ErrorKind::KeyLength(8))
+        ));
+        assert!(matches!(
+            TuyaDevice::create("3.3", None, ip),
+            Err(ErrorKind::KeyRequiredForVersion(TuyaVersion::ThreeThree))
+        ));
+        assert!(TuyaDevice::create("3.1", None, ip).is_ok());
+    }
+
+    #[test]
+    fn builder_sets_options() {
+        let device = TuyaDevice::builder()
+            .version("3.3")
+            .key("bbe88b3f4106d354")
+            .ip(IpAddr::from_str("192.168.0.123").unwrap())
+            .transport(Transport::UDP {
+                local: 0,
+                remote: 6667,
+            })
+            .transport_options(TransportOptions {
+                nodelay: true,
+                keepalive: Some(Duration::from_secs(60)),
+            })
+            .timeout(Duration::from_secs(5))
+            .retries(3, Duration::from_millis(100))
+            .dev_id("002004265ccf7fb1b659")
+            .build()
+            .unwrap();
+        assert_eq!(device.addr, "192.168.0.123:6667".parse().unwrap());
+        assert!(matches!(
+            device.transport,
+            Transport::UDP {
+                local: 0,
+                remote: 6667
+            }
+        ));
+        assert_eq!(
+            device.transport_options.keepalive,
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(device.timeout, Duration::from_secs(5));
+        assert_eq!(device.retries, 3);
+        assert_eq!(device.retry_delay, Duration::from_millis(100));
+        assert_eq!(device.dev_id.as_deref(), Some("002004265ccf7fb1b659"));
+        assert_eq!(device.gw_id, None);
     }
 }
@@ -31,6 +31,7 @@ fn encode_and_decode_message() {
 }
 
 #[test]
+#[cfg(feature = "crypto")]
 fn encode_and_decode_get_message_version_three_three() {
     let payload = create_test_payload();
     let parser = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
@@ -43,6 +44,7 @@ fn encode_and_decode_get_message_version_three_three() {
 }
 
 #[test]
+#[cfg(feature = "crypto")]
 fn encode_and_decode_set_message_version_three_three() {
     let payload = create_test_payload();
     let parser = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
@@ -54,6 +56,32 @@ fn encode_and_decode_set_message_version_three_three() {
     assert_eq!(message_to_encode, decoded[0]);
 }
 
+#[test]
+#[cfg(feature = "crypto")]
+fn encode_and_decode_set_message_version_three_four() {
+    let payload = create_test_payload();
+    let parser = MessageParser::create("3.4", Some("bbe88b3f4106d354")).unwrap();
+    let message_to_encode = Message::new(payload, CommandType::ControlNew, Some(0));
+    let encoded = parser.encode(&message_to_encode, true).unwrap();
+
+    let decoded = parser.parse(&encoded).unwrap();
+
+    assert_eq!(message_to_encode, decoded[0]);
+}
+
+#[test]
+#[cfg(feature = "crypto")]
+fn encode_and_decode_set_message_version_three_five() {
+    let payload = create_test_payload();
+    let parser = MessageParser::create("3.5", Some("bbe88b3f4106d354")).unwrap();
+    let message_to_encode = Message::new(payload, CommandType::Control, Some(0));
+    let encoded = parser.encode(&message_to_encode, true).unwrap();
+
+    let decoded = parser.parse(&encoded).unwrap();
+
+    assert_eq!(message_to_encode, decoded[0]);
+}
+
 #[test]
 fn decode_empty_message() {
     let payload = Payload::String("".to_string());
@@ -0,0 +1,136 @@
+// The fake device encrypts with 3.3
+#![cfg(feature = "crypto")]
+
+use rust_tuyapi::{
+    error::ErrorKind,
+    mesparse::{CommandType, Message, MessageParser},
+    Payload, PayloadStruct, Result, TransportOptions, TuyaDevice, TuyaTransport,
+};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A transport that records what is sent and replies with prepared bytes, each after
+/// read_delay.
+struct FakeTransport {
+    sent: Arc<Mutex<Vec<u8>>>,
+    replies: Vec<Vec<u8>>,
+    read_delay: Duration,
+}
+
+impl TuyaTransport for FakeTransport {
+    fn setup(
+        &self,
+        _addr: SocketAddr,
+        _timeout: Duration,
+        _options: &TransportOptions,
+    ) -> Result<()> {
+        Ok(())
+    }
+    fn do_send(&mut self, buf: &[u8]) -> Result<usize> {
+        self.sent.lock().unwrap().extend(buf);
+        Ok(buf.len())
+    }
+    fn do_read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        thread::sleep(self.read_delay);
+        match self.replies.pop() {
+            Some(reply) => {
+                buf[..reply.len()].copy_from_slice(&reply);
+                Ok(reply.len())
+            }
+            None => Ok(0),
+        }
+    }
+    fn teardown(&self) -> Result<()> {
+        Ok(())
+    }
+    fn error(&self) -> ErrorKind {
+        ErrorKind::BadTcpRead
+    }
+}
+
+#[test]
+fn set_over_fake_transport() {
+    let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+    let reply = Message::new(
+        Payload::String("".to_string()),
+        CommandType::Control,
+        Some(1),
+    );
+    let sent = Arc::new(Mutex::new(vec![]));
+    let transport = FakeTransport {
+        sent: sent.clone(),
+        replies: vec![mp.encode(&reply, true).unwrap()],
+        read_delay: Duration::ZERO,
+    };
+    let mut device = TuyaDevice::create_with_custom_transport(
+        mp.clone(),
+        "192.168.0.123:6668".parse().unwrap(),
+        Box::new(transport),
+    )
+    .unwrap();
+
+    let payload = PayloadStruct::builder("002004265ccf7fb1b659")
+        .dp("1", true)
+        .build();
+    device.set(payload.clone(), 1).unwrap();
+
+    let written = mp.parse(&sent.lock().unwrap()).unwrap();
+    assert_eq!(
+        written,
+        vec![Message::new(payload, CommandType::Control, Some(1))]
+    );
+
+    // The transport can not be reopened once it is closed
+    device.disconnect().unwrap();
+    assert!(matches!(
+        device.get(
+            Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+            2
+        ),
+        Err(ErrorKind::CustomTransportClosed)
+    ));
+}
+
+#[test]
+fn sequence_mismatch_is_kept_after_total_timeout() {
+    let mp = MessageParser::create("3.3", Some("bbe88b3f4106d354")).unwrap();
+    let reply = Message::new(
+        PayloadStruct::builder("002004265ccf7fb1b659")
+            .dp("1", true)
+            .build(),
+        CommandType::DpQuery,
+        Some(1),
+    );
+    let transport = FakeTransport {
+        sent: Arc::new(Mutex::new(vec![])),
+        replies: vec![mp.encode(&reply, true).unwrap()],
+        read_delay: Duration::from_millis(100),
+    };
+    let mut device = TuyaDevice::create_with_custom_transport(
+        mp,
+        "192.168.0.123:6668".parse().unwrap(),
+        Box::new(transport),
+    )
+    .unwrap()
+    .with_sequence_check(true);
+    device.set_total_timeout(Some(Duration::from_millis(10)));
+
+    // The reply arrives after the deadline, but the failure is not a timeout
+    let result = device.get(
+        Payload::query("002004265ccf7fb1b659", "002004265ccf7fb1b659"),
+        2,
+    );
+    assert!(
+        matches!(
+            result,
+            Err(ErrorKind::SequenceMismatch {
+                expected: 2,
+                got: 1
+            })
+        ),
+        "{:?}",
+        result
+    );
+}
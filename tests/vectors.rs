@@ -0,0 +1,64 @@
+//! Checks the regression fixtures in tests/vectors against the parser. Each file holds the frames
+//! of one protocol version, see tests/vectors/README.md for the format.
+#![cfg(feature = "crypto")]
+
+use rust_tuyapi::mesparse::{Message, MessageParser};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct VectorFile {
+    version: String,
+    vectors: Vec<Vector>,
+}
+
+#[derive(Deserialize)]
+struct Vector {
+    name: String,
+    key: Option<String>,
+    encrypt: bool,
+    exact: bool,
+    frame: String,
+    message: Message,
+}
+
+fn check(version: &str, vector: &Vector) {
+    let name = format!("{} {}", version, vector.name);
+    let mp = MessageParser::create(version, vector.key.as_deref()).unwrap();
+    let frame = hex::decode(&vector.frame).unwrap();
+    let parsed = mp
+        .parse(&frame)
+        .unwrap_or_else(|e| panic!("{}: {}", name, e));
+    assert_eq!(parsed.len(), 1, "{}: parse", name);
+    assert_eq!(parsed[0], vector.message, "{}: parse", name);
+
+    let encoded = mp.encode(&vector.message, vector.encrypt).unwrap();
+    if vector.exact {
+        assert_eq!(hex::encode(&encoded), vector.frame, "{}: encode", name);
+    }
+    assert_eq!(
+        mp.parse(&encoded).unwrap()[0],
+        vector.message,
+        "{}: round trip",
+        name
+    );
+}
+
+#[test]
+fn vectors_of_all_versions() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors");
+    let mut versions = vec![];
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let file: VectorFile = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+            for vector in &file.vectors {
+                check(&file.version, vector);
+            }
+            versions.push(file.version);
+        }
+    }
+    versions.sort();
+    assert_eq!(versions, ["3.1", "3.2", "3.3", "3.4", "3.5"]);
+}